@@ -1,18 +1,47 @@
 pub mod gc_heap;
+pub mod image;
 mod interner;
-use gc_heap::GcHeap;
+pub mod readtable;
+pub mod roots;
+pub mod spans;
+use gc_heap::{GcHeap, Handle};
 use interner::Interner;
+use readtable::Readtable;
+use roots::{Root, RootSet};
+use spans::SpanTable;
+
+#[derive(Default)]
+pub struct ContextOptions {
+    /// Maximum number of live heap cells, `None` for no limit.
+    pub max_cells: Option<usize>,
+}
 
 pub struct Context {
     pub heap: GcHeap,
     pub interner: Interner,
+    pub roots: RootSet,
+    /// The global environment, created by the first `Evaluator`.
+    pub global_env: Option<Handle>,
+    pub readtable: Readtable,
+    /// Where the parser read each pair it allocated.
+    pub spans: SpanTable,
 }
 
 impl Context {
-    pub fn new() -> Self {
+    pub fn new(options: ContextOptions) -> Self {
         Self {
-            heap: GcHeap::new(),
+            heap: GcHeap::new(options.max_cells),
             interner: Interner::new(),
+            roots: RootSet::default(),
+            global_env: None,
+            readtable: Readtable::default(),
+            spans: SpanTable::default(),
         }
     }
+
+    /// Keeps `handle` alive across collections for as long as the returned
+    /// guard lives.
+    pub fn root(&self, handle: Handle) -> Root {
+        self.roots.root(handle)
+    }
 }
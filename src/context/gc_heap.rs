@@ -1,70 +1,310 @@
-use crate::sexp::Sexp;
+use crate::sexp::{Sexp, Symbol};
+use std::borrow::Cow;
 use std::vec;
 
-const MAX_HEAP_SIZE: usize = 1000;
+const INITIAL_GC_THRESHOLD: usize = 1000;
 
-pub type Handle = usize;
+/// A Lisp value. Integers, nil, symbols, booleans, characters, `#inert` and
+/// `#ignore` are stored inline; everything else lives in a heap cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Handle {
+    Nil,
+    Integer(i64),
+    Symbol(Symbol),
+    Boolean(bool),
+    Char(char),
+    Inert,
+    Ignore,
+    Cell(CellId),
+}
+
+/// The address of a heap cell. The generation is bumped every time the cell
+/// is freed, so an id that outlived its cell can be told apart from an id
+/// to whatever was allocated in its place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellId {
+    index: u32,
+    generation: u32,
+}
+
+impl CellId {
+    pub fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl Handle {
+    /// The inline representation of `sexp`, if it has one.
+    fn immediate(sexp: &Sexp) -> Option<Handle> {
+        match sexp {
+            Sexp::Nil => Some(Handle::Nil),
+            Sexp::Integer(i) => Some(Handle::Integer(*i)),
+            Sexp::Symbol(s) => Some(Handle::Symbol(*s)),
+            Sexp::Boolean(b) => Some(Handle::Boolean(*b)),
+            Sexp::Char(c) => Some(Handle::Char(*c)),
+            Sexp::Inert => Some(Handle::Inert),
+            Sexp::Ignore => Some(Handle::Ignore),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeapError {
+    UnknownHandle(CellId),
+    DanglingHandle(CellId),
+    NotACell(Handle),
+}
+
+impl std::fmt::Display for HeapError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnknownHandle(h) => write!(fmt, "unknown handle #{}", h.index),
+            Self::DanglingHandle(h) => write!(
+                fmt,
+                "dangling handle #{} (generation {})",
+                h.index, h.generation
+            ),
+            Self::NotACell(h) => write!(fmt, "{:?} is not a heap cell", h),
+        }
+    }
+}
 
 pub struct Cell {
     val: Option<Sexp>,
     mark: bool,
+    generation: u32,
 }
 
 impl Cell {
     fn new(sexp: Sexp) -> Self {
-        return Self {
+        Self {
             val: Some(sexp),
             mark: false,
-        };
+            generation: 0,
+        }
     }
 }
 
 pub struct GcHeap {
     cells: Vec<Cell>,
-    free_list: Vec<Handle>,
+    free_list: Vec<usize>,
+    next_gc: usize,
+    max_cells: Option<usize>,
+    allocations: usize,
 }
 
 impl GcHeap {
-    pub fn new() -> Self {
+    pub fn new(max_cells: Option<usize>) -> Self {
         Self {
             cells: vec![],
             free_list: vec![],
+            next_gc: INITIAL_GC_THRESHOLD,
+            max_cells,
+            allocations: 0,
         }
     }
 
-    pub fn alloc(self: &mut Self, sexp: Sexp) -> Handle {
-        match self.free_list.pop() {
-            Some(handle) => {
-                self.cells.get_mut(handle).expect("unknown id").val = Some(sexp);
-                handle
+    /// Returns a handle to `sexp`. Only values without an inline
+    /// representation take up a cell.
+    pub fn alloc(&mut self, sexp: Sexp) -> Handle {
+        if let Some(handle) = Handle::immediate(&sexp) {
+            return handle;
+        }
+        self.allocations += 1;
+        let index = match self.free_list.pop() {
+            Some(index) => {
+                self.cells[index].val = Some(sexp);
+                index
             }
             None => {
-                let handle = self.cells.len();
                 self.cells.push(Cell::new(sexp));
-                handle
+                self.cells.len() - 1
             }
+        };
+        Handle::Cell(CellId {
+            index: index as u32,
+            generation: self.cells[index].generation,
+        })
+    }
+
+    fn get_cell(&self, id: CellId) -> Result<&Sexp, HeapError> {
+        let cell = self
+            .cells
+            .get(id.index as usize)
+            .ok_or(HeapError::UnknownHandle(id))?;
+        if cell.generation != id.generation {
+            return Err(HeapError::DanglingHandle(id));
+        }
+        cell.val.as_ref().ok_or(HeapError::DanglingHandle(id))
+    }
+
+    pub fn try_get_ref(&self, handle: Handle) -> Result<Cow<'_, Sexp>, HeapError> {
+        match handle {
+            Handle::Nil => Ok(Cow::Owned(Sexp::Nil)),
+            Handle::Integer(i) => Ok(Cow::Owned(Sexp::Integer(i))),
+            Handle::Symbol(s) => Ok(Cow::Owned(Sexp::Symbol(s))),
+            Handle::Boolean(b) => Ok(Cow::Owned(Sexp::Boolean(b))),
+            Handle::Char(c) => Ok(Cow::Owned(Sexp::Char(c))),
+            Handle::Inert => Ok(Cow::Owned(Sexp::Inert)),
+            Handle::Ignore => Ok(Cow::Owned(Sexp::Ignore)),
+            Handle::Cell(id) => self.get_cell(id).map(Cow::Borrowed),
         }
     }
 
-    pub fn get_ref(self: &Self, handle: Handle) -> &Sexp {
-        self.cells
-            .get(handle)
-            .expect("unknown id")
-            .val
-            .as_ref()
-            .expect("empty cell")
+    /// Only cells can be mutated, immediate values are rejected.
+    pub fn try_get_mut_ref(&mut self, handle: Handle) -> Result<&mut Sexp, HeapError> {
+        let id = match handle {
+            Handle::Cell(id) => id,
+            _ => return Err(HeapError::NotACell(handle)),
+        };
+        let cell = self
+            .cells
+            .get_mut(id.index as usize)
+            .ok_or(HeapError::UnknownHandle(id))?;
+        if cell.generation != id.generation {
+            return Err(HeapError::DanglingHandle(id));
+        }
+        cell.val.as_mut().ok_or(HeapError::DanglingHandle(id))
     }
 
-    pub fn get_mut_ref(self: &mut Self, handle: Handle) -> &mut Sexp {
-        self.cells
-            .get_mut(handle)
-            .expect("unknown id")
-            .val
-            .as_mut()
-            .expect("empty cell")
+    /// Like `try_get_ref`, but only for handles that are known to be live:
+    /// rooted, on the evaluator's stacks, or reached from one of those since
+    /// the last collection. A dangling handle there means the heap is
+    /// corrupt, so this panics. Builtins, which get their handles from user
+    /// code, go through `try_get_ref` instead.
+    pub fn get_ref(&self, handle: Handle) -> Cow<'_, Sexp> {
+        match self.try_get_ref(handle) {
+            Ok(sexp) => sexp,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// `try_get_mut_ref` for handles that are known to be live, see `get_ref`.
+    pub fn get_mut_ref(&mut self, handle: Handle) -> &mut Sexp {
+        match self.try_get_mut_ref(handle) {
+            Ok(sexp) => sexp,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Total number of cells allocated since the heap was created.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// Rebuilds a heap from the generation and contents of every cell, in
+    /// index order. Empty cells go onto the free list.
+    pub fn from_slots(slots: Vec<(u32, Option<Sexp>)>, max_cells: Option<usize>) -> Self {
+        let mut heap = Self::new(max_cells);
+        for (index, (generation, val)) in slots.into_iter().enumerate() {
+            if val.is_none() {
+                heap.free_list.push(index);
+            }
+            heap.cells.push(Cell {
+                val,
+                mark: false,
+                generation,
+            });
+        }
+        heap.free_list.reverse();
+        heap.next_gc = INITIAL_GC_THRESHOLD.max(2 * heap.live_cells());
+        heap
+    }
+
+    /// The generation and contents of every cell, in index order.
+    pub fn slots(&self) -> impl Iterator<Item = (u32, Option<&Sexp>)> {
+        self.cells.iter().map(|c| (c.generation, c.val.as_ref()))
+    }
+
+    pub fn live_cells(&self) -> usize {
+        self.cells.len() - self.free_list.len()
+    }
+
+    pub fn needs_collection(&self) -> bool {
+        self.live_cells() >= self.next_gc || self.is_exhausted()
+    }
+
+    pub fn max_cells(&self) -> Option<usize> {
+        self.max_cells
+    }
+
+    /// True when more cells are live than the configured maximum allows.
+    pub fn is_exhausted(&self) -> bool {
+        match self.max_cells {
+            Some(max) => self.live_cells() > max,
+            None => false,
+        }
+    }
+
+    /// Marks every cell reachable from `roots`, then puts every unmarked cell
+    /// back onto the free list. Returns the number of cells freed.
+    pub fn collect<I: IntoIterator<Item = Handle>>(&mut self, roots: I) -> usize {
+        let mut gray: Vec<Handle> = roots.into_iter().collect();
+        while let Some(handle) = gray.pop() {
+            let id = match handle {
+                Handle::Cell(id) => id,
+                _ => continue,
+            };
+            let cell = &mut self.cells[id.index as usize];
+            if cell.mark || cell.generation != id.generation {
+                continue;
+            }
+            cell.mark = true;
+            if let Some(val) = &cell.val {
+                val.mark(&mut gray);
+            }
+        }
+
+        let mut freed = 0;
+        for (index, cell) in self.cells.iter_mut().enumerate() {
+            if cell.mark {
+                cell.mark = false;
+            } else if cell.val.is_some() {
+                cell.val = None;
+                cell.generation = cell.generation.wrapping_add(1);
+                self.free_list.push(index);
+                freed += 1;
+            }
+        }
+        self.next_gc = INITIAL_GC_THRESHOLD.max(2 * self.live_cells());
+        freed
+    }
+
+    /// Walks every cell reachable from `roots` and checks that each handle it
+    /// holds still points to a live cell. Returns the number of cells visited.
+    pub fn verify<I: IntoIterator<Item = Handle>>(&self, roots: I) -> Result<usize, HeapError> {
+        let mut visited = vec![false; self.cells.len()];
+        let mut gray: Vec<Handle> = roots.into_iter().collect();
+        let mut count = 0;
+        while let Some(handle) = gray.pop() {
+            let id = match handle {
+                Handle::Cell(id) => id,
+                _ => continue,
+            };
+            let sexp = self.get_cell(id)?;
+            let seen = &mut visited[id.index as usize];
+            if *seen {
+                continue;
+            }
+            *seen = true;
+            count += 1;
+            sexp.mark(&mut gray);
+        }
+        Ok(count)
     }
 }
 
+/// Implemented by everything that can hold handles to other cells.
 pub trait Mark {
-    fn mark(&mut self);
+    /// Pushes the handles directly reachable from `self` onto `gray`.
+    fn mark(&self, gray: &mut Vec<Handle>);
 }
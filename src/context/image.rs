@@ -0,0 +1,574 @@
+//! Heap images: a whole `Context` written to a file so that it can be loaded
+//! back without re-reading the sources it was built from.
+//!
+//! An image is the magic bytes, a format version, the symbol names in symbol
+//! order, the gensym counter, every heap cell and the global environment.
+//! Each symbol is flagged as interned or not, and free cells are written too
+//! so that handles stay valid. All integers are little-endian. Builtins are
+//! stored by name and re-linked against `builtins::BUILTINS` on load.
+
+use super::gc_heap::{CellId, GcHeap, Handle};
+use super::interner::Interner;
+use super::readtable::Readtable;
+use super::roots::RootSet;
+use super::spans::{SourceFile, SpanTable};
+use super::{Context, ContextOptions};
+use crate::evaluator::builtins::lookup_builtin;
+use crate::evaluator::env::Env;
+use crate::number::bigint::BigInt;
+use crate::number::rational::Rational;
+use crate::sexp::hash_table::{Entry, HashTable, TableKind};
+use crate::sexp::port::Port;
+use crate::sexp::{Closure, Sexp, Symbol};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+const MAGIC: &[u8; 8] = b"MAXLISP\0";
+const VERSION: u32 = 4;
+
+const HANDLE_NIL: u8 = 0;
+const HANDLE_INTEGER: u8 = 1;
+const HANDLE_SYMBOL: u8 = 2;
+const HANDLE_CELL: u8 = 3;
+const HANDLE_BOOLEAN: u8 = 4;
+const HANDLE_CHAR: u8 = 5;
+const HANDLE_INERT: u8 = 6;
+const HANDLE_IGNORE: u8 = 7;
+
+const CELL_FREE: u8 = 0;
+const CELL_INTEGER: u8 = 1;
+const CELL_SYMBOL: u8 = 2;
+const CELL_STRING: u8 = 3;
+const CELL_PAIR: u8 = 4;
+/// Never written, since `alloc` keeps `()` inline; rejected on load.
+const CELL_NIL: u8 = 5;
+const CELL_ENV: u8 = 6;
+const CELL_BUILTIN: u8 = 7;
+const CELL_CLOSURE: u8 = 8;
+const CELL_WRAPPED_PROC: u8 = 9;
+const CELL_REAL: u8 = 10;
+const CELL_BIGINT: u8 = 11;
+const CELL_RATIONAL: u8 = 12;
+const CELL_VECTOR: u8 = 13;
+const CELL_HASH_TABLE: u8 = 14;
+const CELL_PORT: u8 = 15;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Corrupt(&'static str),
+    UnknownBuiltin(String),
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "{}", e),
+            Self::BadMagic => write!(fmt, "not a maxlisp image"),
+            Self::UnsupportedVersion(v) => write!(fmt, "unsupported image version {}", v),
+            Self::Corrupt(what) => write!(fmt, "corrupt image: {}", what),
+            Self::UnknownBuiltin(name) => write!(fmt, "unknown builtin `{}`", name),
+        }
+    }
+}
+
+struct ImageWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> ImageWriter<W> {
+    fn u8(&mut self, v: u8) -> io::Result<()> {
+        self.w.write_all(&[v])
+    }
+
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.w.write_all(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> io::Result<()> {
+        self.w.write_all(&v.to_le_bytes())
+    }
+
+    fn i64(&mut self, v: i64) -> io::Result<()> {
+        self.w.write_all(&v.to_le_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.u64(s.len() as u64)?;
+        self.w.write_all(s.as_bytes())
+    }
+
+    fn bigint(&mut self, b: &BigInt) -> io::Result<()> {
+        self.u8(b.is_negative() as u8)?;
+        self.u64(b.limbs().len() as u64)?;
+        for limb in b.limbs() {
+            self.u32(*limb)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, h: Handle) -> io::Result<()> {
+        match h {
+            Handle::Nil => self.u8(HANDLE_NIL),
+            Handle::Integer(i) => {
+                self.u8(HANDLE_INTEGER)?;
+                self.i64(i)
+            }
+            Handle::Symbol(s) => {
+                self.u8(HANDLE_SYMBOL)?;
+                self.u64(s)
+            }
+            Handle::Boolean(b) => {
+                self.u8(HANDLE_BOOLEAN)?;
+                self.u8(b as u8)
+            }
+            Handle::Char(c) => {
+                self.u8(HANDLE_CHAR)?;
+                self.u32(c as u32)
+            }
+            Handle::Inert => self.u8(HANDLE_INERT),
+            Handle::Ignore => self.u8(HANDLE_IGNORE),
+            Handle::Cell(id) => {
+                self.u8(HANDLE_CELL)?;
+                self.u32(id.index())?;
+                self.u32(id.generation())
+            }
+        }
+    }
+
+    fn cell(&mut self, val: Option<&Sexp>) -> io::Result<()> {
+        let sexp = match val {
+            Some(sexp) => sexp,
+            None => return self.u8(CELL_FREE),
+        };
+        match sexp {
+            Sexp::Integer(i) => {
+                self.u8(CELL_INTEGER)?;
+                self.i64(*i)
+            }
+            Sexp::BigInt(b) => {
+                self.u8(CELL_BIGINT)?;
+                self.bigint(b)
+            }
+            Sexp::Rational(q) => {
+                self.u8(CELL_RATIONAL)?;
+                self.bigint(q.numerator())?;
+                self.bigint(q.denominator())
+            }
+            Sexp::Real(r) => {
+                self.u8(CELL_REAL)?;
+                self.u64(r.to_bits())
+            }
+            Sexp::Symbol(s) => {
+                self.u8(CELL_SYMBOL)?;
+                self.u64(*s)
+            }
+            Sexp::String(s) => {
+                self.u8(CELL_STRING)?;
+                self.str(s)
+            }
+            Sexp::Pair(car, cdr) => {
+                self.u8(CELL_PAIR)?;
+                self.handle(*car)?;
+                self.handle(*cdr)
+            }
+            Sexp::Vector(v) => {
+                self.u8(CELL_VECTOR)?;
+                self.u64(v.len() as u64)?;
+                for h in v {
+                    self.handle(*h)?;
+                }
+                Ok(())
+            }
+            Sexp::HashTable(t) => {
+                self.u8(CELL_HASH_TABLE)?;
+                self.u8(t.kind() as u8)?;
+                self.u64(t.len() as u64)?;
+                for entry in t.entries() {
+                    self.u64(entry.hash)?;
+                    self.handle(entry.key)?;
+                    self.handle(entry.val)?;
+                }
+                Ok(())
+            }
+            Sexp::Port(port) => {
+                self.u8(CELL_PORT)?;
+                self.str(port.source().name())?;
+                self.str(port.text())?;
+                self.u64(port.pos() as u64)
+            }
+            Sexp::Nil | Sexp::Boolean(_) | Sexp::Char(_) | Sexp::Inert | Sexp::Ignore => {
+                unreachable!("immediates are never stored in cells")
+            }
+            Sexp::Env(env) => {
+                self.u8(CELL_ENV)?;
+                self.handle(env.outer.unwrap_or(Handle::Nil))?;
+                let bindings: Vec<_> = env.bindings().collect();
+                self.u64(bindings.len() as u64)?;
+                for (sym, val) in bindings {
+                    self.u64(sym)?;
+                    self.handle(val)?;
+                }
+                Ok(())
+            }
+            Sexp::Builtin(_, name) => {
+                self.u8(CELL_BUILTIN)?;
+                self.str(name)
+            }
+            Sexp::Closure(c) => {
+                self.u8(CELL_CLOSURE)?;
+                self.handle(c.env)?;
+                self.u64(c.vars.len() as u64)?;
+                for var in &c.vars {
+                    self.handle(*var)?;
+                }
+                self.handle(c.sym)?;
+                self.handle(c.body)
+            }
+            Sexp::WrappedProc(p) => {
+                self.u8(CELL_WRAPPED_PROC)?;
+                self.handle(*p)
+            }
+        }
+    }
+}
+
+struct ImageReader<R: Read> {
+    r: R,
+}
+
+impl<R: Read> ImageReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], ImageError> {
+        let mut buf = [0; N];
+        self.r.read_exact(&mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ImageError::Corrupt("truncated"),
+            _ => ImageError::Io(e),
+        })?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ImageError> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ImageError> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, ImageError> {
+        Ok(i64::from_le_bytes(self.bytes()?))
+    }
+
+    fn str(&mut self) -> Result<String, ImageError> {
+        let len = self.u64()?;
+        let mut buf = vec![];
+        (&mut self.r).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(ImageError::Corrupt("truncated"));
+        }
+        String::from_utf8(buf).map_err(|_| ImageError::Corrupt("invalid utf-8"))
+    }
+
+    fn bigint(&mut self) -> Result<BigInt, ImageError> {
+        let negative = self.u8()? != 0;
+        let mut limbs = vec![];
+        for _ in 0..self.u64()? {
+            limbs.push(self.u32()?);
+        }
+        Ok(BigInt::from_parts(negative, limbs))
+    }
+
+    fn handle(&mut self) -> Result<Handle, ImageError> {
+        match self.u8()? {
+            HANDLE_NIL => Ok(Handle::Nil),
+            HANDLE_INTEGER => Ok(Handle::Integer(self.i64()?)),
+            HANDLE_SYMBOL => Ok(Handle::Symbol(self.u64()?)),
+            HANDLE_BOOLEAN => Ok(Handle::Boolean(self.u8()? != 0)),
+            HANDLE_CHAR => char::from_u32(self.u32()?)
+                .map(Handle::Char)
+                .ok_or(ImageError::Corrupt("invalid character")),
+            HANDLE_INERT => Ok(Handle::Inert),
+            HANDLE_IGNORE => Ok(Handle::Ignore),
+            HANDLE_CELL => {
+                let index = self.u32()?;
+                let generation = self.u32()?;
+                Ok(Handle::Cell(CellId::new(index, generation)))
+            }
+            _ => Err(ImageError::Corrupt("unknown handle tag")),
+        }
+    }
+
+    fn cell(&mut self) -> Result<Option<Sexp>, ImageError> {
+        let sexp = match self.u8()? {
+            CELL_FREE => return Ok(None),
+            CELL_INTEGER => Sexp::Integer(self.i64()?),
+            CELL_BIGINT => Sexp::BigInt(self.bigint()?),
+            CELL_RATIONAL => {
+                let num = self.bigint()?;
+                let den = self.bigint()?;
+                match Rational::new(num, den) {
+                    Some(q) => Sexp::Rational(q),
+                    None => return Err(ImageError::Corrupt("zero denominator")),
+                }
+            }
+            CELL_REAL => Sexp::Real(f64::from_bits(self.u64()?)),
+            CELL_SYMBOL => Sexp::Symbol(self.u64()?),
+            CELL_STRING => Sexp::String(self.str()?),
+            CELL_PAIR => Sexp::Pair(self.handle()?, self.handle()?),
+            CELL_VECTOR => {
+                let mut v = vec![];
+                for _ in 0..self.u64()? {
+                    v.push(self.handle()?);
+                }
+                Sexp::Vector(v)
+            }
+            CELL_HASH_TABLE => {
+                let kind = match self.u8()? {
+                    0 => TableKind::Eq,
+                    1 => TableKind::Eqv,
+                    2 => TableKind::Equal,
+                    _ => return Err(ImageError::Corrupt("unknown hash table kind")),
+                };
+                let mut entries = vec![];
+                for _ in 0..self.u64()? {
+                    entries.push(Entry {
+                        hash: self.u64()?,
+                        key: self.handle()?,
+                        val: self.handle()?,
+                    });
+                }
+                Sexp::HashTable(HashTable::from_entries(kind, entries))
+            }
+            CELL_PORT => {
+                let name = self.str()?;
+                let text = self.str()?;
+                let pos = self.u64()? as usize;
+                if !text.is_char_boundary(pos) {
+                    return Err(ImageError::Corrupt("bad port position"));
+                }
+                Sexp::Port(Port::new(Rc::new(SourceFile::new(&name, text)), pos))
+            }
+            CELL_NIL => return Err(ImageError::Corrupt("nil stored in a cell")),
+            CELL_ENV => {
+                let outer = match self.handle()? {
+                    Handle::Nil => None,
+                    h => Some(h),
+                };
+                let mut env = Env::new(outer);
+                for _ in 0..self.u64()? {
+                    let sym = self.u64()?;
+                    env.def(sym, self.handle()?);
+                }
+                Sexp::Env(env)
+            }
+            CELL_BUILTIN => {
+                let name = self.str()?;
+                match lookup_builtin(&name) {
+                    Some((func, name)) => Sexp::Builtin(func, name),
+                    None => return Err(ImageError::UnknownBuiltin(name)),
+                }
+            }
+            CELL_CLOSURE => {
+                let env = self.handle()?;
+                let mut vars = vec![];
+                for _ in 0..self.u64()? {
+                    vars.push(self.handle()?);
+                }
+                Sexp::Closure(Closure {
+                    env,
+                    vars,
+                    sym: self.handle()?,
+                    body: self.handle()?,
+                })
+            }
+            CELL_WRAPPED_PROC => Sexp::WrappedProc(self.handle()?),
+            _ => return Err(ImageError::Corrupt("unknown cell tag")),
+        };
+        Ok(Some(sexp))
+    }
+}
+
+impl Context {
+    /// Writes the interner, the heap, the global environment and the
+    /// readtable to `w`. Host roots are not part of the image.
+    pub fn save_image<W: Write>(&self, w: W) -> Result<(), ImageError> {
+        let mut w = ImageWriter { w };
+        w.w.write_all(MAGIC)?;
+        w.u32(VERSION)?;
+
+        let names: Vec<&str> = self.interner.names().collect();
+        w.u64(names.len() as u64)?;
+        for (sym, name) in names.into_iter().enumerate() {
+            w.u8(self.interner.is_interned(sym as Symbol) as u8)?;
+            w.str(name)?;
+        }
+        w.u64(self.interner.gensym_counter())?;
+
+        let slots: Vec<_> = self.heap.slots().collect();
+        w.u64(slots.len() as u64)?;
+        for (generation, val) in slots {
+            w.u32(generation)?;
+            w.cell(val)?;
+        }
+
+        match self.global_env {
+            Some(env) => {
+                w.u8(1)?;
+                w.handle(env)?;
+            }
+            None => w.u8(0)?,
+        }
+
+        for table in [
+            self.readtable.macros().collect::<Vec<_>>(),
+            self.readtable.dispatch_macros().collect(),
+        ] {
+            w.u64(table.len() as u64)?;
+            for (c, proc) in table {
+                w.u32(c as u32)?;
+                w.handle(proc)?;
+            }
+        }
+        w.w.flush()?;
+        Ok(())
+    }
+
+    /// Reads back a context written by `save_image`.
+    pub fn load_image<R: Read>(r: R, options: ContextOptions) -> Result<Context, ImageError> {
+        let mut r = ImageReader { r };
+        if &r.bytes::<8>()? != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let mut interner = Interner::new();
+        for sym in 0..r.u64()? {
+            let interned = r.u8()? != 0;
+            let name = r.str()?;
+            let got = if interned {
+                interner.intern(&name)
+            } else {
+                interner.make_symbol(&name)
+            };
+            if got != sym {
+                return Err(ImageError::Corrupt("duplicate symbol name"));
+            }
+        }
+        interner.set_gensym_counter(r.u64()?);
+
+        let mut slots = vec![];
+        for _ in 0..r.u64()? {
+            let generation = r.u32()?;
+            slots.push((generation, r.cell()?));
+        }
+
+        let global_env = match r.u8()? {
+            0 => None,
+            1 => Some(r.handle()?),
+            _ => return Err(ImageError::Corrupt("bad global environment")),
+        };
+
+        let mut readtable = Readtable::default();
+        for dispatch in [false, true] {
+            for _ in 0..r.u64()? {
+                let c = char::from_u32(r.u32()?).ok_or(ImageError::Corrupt("invalid character"))?;
+                let proc = r.handle()?;
+                if dispatch {
+                    readtable.set_dispatch(c, proc);
+                } else {
+                    readtable.set_macro(c, proc);
+                }
+            }
+        }
+
+        let ctx = Context {
+            heap: GcHeap::from_slots(slots, options.max_cells),
+            interner,
+            roots: RootSet::default(),
+            global_env,
+            readtable,
+            spans: SpanTable::default(),
+        };
+        ctx.heap
+            .verify(ctx.readtable.handles())
+            .map_err(|_| ImageError::Corrupt("dangling handle"))?;
+        if let Some(env) = global_env {
+            ctx.heap
+                .verify([env])
+                .map_err(|_| ImageError::Corrupt("dangling handle"))?;
+            if !matches!(ctx.heap.get_ref(env).as_ref(), Sexp::Env(_)) {
+                return Err(ImageError::Corrupt("bad global environment"));
+            }
+        }
+        for (_, cell) in ctx.heap.slots() {
+            let env = match cell {
+                Some(Sexp::Env(env)) => env.outer,
+                Some(Sexp::Closure(c)) => Some(c.env),
+                _ => None,
+            };
+            if let Some(env) = env {
+                match ctx.heap.try_get_ref(env).as_deref() {
+                    Ok(Sexp::Env(_)) => {}
+                    _ => return Err(ImageError::Corrupt("bad environment")),
+                }
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reload(ctx: &Context) -> Result<Context, ImageError> {
+        let mut image = vec![];
+        ctx.save_image(&mut image).unwrap();
+        Context::load_image(&image[..], ContextOptions::default())
+    }
+
+    #[test]
+    fn rejects_environment_links_to_non_environments() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let pair = ctx.heap.alloc(Sexp::Pair(Handle::Nil, Handle::Nil));
+        ctx.heap.alloc(Sexp::Env(Env::new(Some(pair))));
+        assert!(matches!(reload(&ctx), Err(ImageError::Corrupt(_))));
+
+        let mut ctx = Context::new(ContextOptions::default());
+        ctx.heap.alloc(Sexp::Closure(Closure {
+            env: Handle::Integer(1),
+            vars: vec![],
+            sym: Handle::Ignore,
+            body: Handle::Nil,
+        }));
+        assert!(matches!(reload(&ctx), Err(ImageError::Corrupt(_))));
+    }
+
+    #[test]
+    fn round_trips_closures() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let env = ctx.heap.alloc(Sexp::Env(Env::new(None)));
+        ctx.heap.alloc(Sexp::Closure(Closure {
+            env,
+            vars: vec![],
+            sym: Handle::Ignore,
+            body: Handle::Nil,
+        }));
+        assert!(reload(&ctx).is_ok());
+    }
+}
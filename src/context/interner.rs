@@ -1,36 +1,66 @@
 use crate::sexp::Symbol;
 use std::collections::HashMap;
+use std::rc::Rc;
 pub struct Interner {
-    strings: HashMap<String, Symbol>,
-    counter: Symbol,
+    strings: HashMap<Rc<str>, Symbol>,
+    names: Vec<Rc<str>>,
+    gensym_counter: u64,
 }
 
 impl Interner {
     pub fn new() -> Self {
-        return Self {
+        Self {
             strings: HashMap::new(),
-            counter: 0,
-        };
-    }
-
-    pub fn intern(self: &mut Self, s: &str) -> Symbol {
-        let s = String::from(s);
-        if self.strings.contains_key(&s) {
-            self.strings[&s]
-        } else {
-            let sym = self.counter;
-            self.counter += 1;
-            self.strings.insert(s, sym);
-            sym
+            names: vec![],
+            gensym_counter: 0,
         }
     }
 
-    pub fn string_from_symbol(self: &Self, s: Symbol) -> Option<&String> {
-        for (k, v) in self.strings.iter() {
-            if *v == s {
-                return Some(k);
-            }
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        if let Some(sym) = self.strings.get(s) {
+            return *sym;
         }
-        return None;
+        let sym = self.names.len() as Symbol;
+        let name: Rc<str> = Rc::from(s);
+        self.names.push(name.clone());
+        self.strings.insert(name, sym);
+        sym
+    }
+
+    /// Creates a fresh symbol named `s` that `intern` will never return.
+    pub fn make_symbol(&mut self, s: &str) -> Symbol {
+        let sym = self.names.len() as Symbol;
+        self.names.push(Rc::from(s));
+        sym
+    }
+
+    pub fn gensym(&mut self) -> Symbol {
+        let name = format!("g{}", self.gensym_counter);
+        self.gensym_counter += 1;
+        self.make_symbol(&name)
+    }
+
+    pub fn gensym_counter(&self) -> u64 {
+        self.gensym_counter
+    }
+
+    pub fn set_gensym_counter(&mut self, counter: u64) {
+        self.gensym_counter = counter;
+    }
+
+    pub fn is_interned(&self, s: Symbol) -> bool {
+        match self.string_from_symbol(s) {
+            Some(name) => self.strings.get(name) == Some(&s),
+            None => false,
+        }
+    }
+
+    pub fn string_from_symbol(&self, s: Symbol) -> Option<&str> {
+        self.names.get(s as usize).map(|name| &**name)
+    }
+
+    /// Every interned name, ordered by symbol.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| &**name)
     }
 }
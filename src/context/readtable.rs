@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use super::gc_heap::Handle;
+
+/// Maps characters to the Lisp procedures that read the syntax they start.
+/// A reader macro on `c` runs when a token starts with `c`, and `c` also ends
+/// the token before it. A dispatch macro on `c` runs on `#c`. Both receive a
+/// port positioned right after the macro characters and return a datum.
+#[derive(Default)]
+pub struct Readtable {
+    macros: HashMap<char, Handle>,
+    dispatch: HashMap<char, Handle>,
+}
+
+impl Readtable {
+    pub fn set_macro(&mut self, c: char, proc: Handle) {
+        self.macros.insert(c, proc);
+    }
+
+    pub fn set_dispatch(&mut self, c: char, proc: Handle) {
+        self.dispatch.insert(c, proc);
+    }
+
+    pub fn get_macro(&self, c: char) -> Option<Handle> {
+        self.macros.get(&c).copied()
+    }
+
+    pub fn get_dispatch(&self, c: char) -> Option<Handle> {
+        self.dispatch.get(&c).copied()
+    }
+
+    pub fn macros(&self) -> impl Iterator<Item = (char, Handle)> + '_ {
+        self.macros.iter().map(|(c, h)| (*c, *h))
+    }
+
+    pub fn dispatch_macros(&self) -> impl Iterator<Item = (char, Handle)> + '_ {
+        self.dispatch.iter().map(|(c, h)| (*c, *h))
+    }
+
+    /// The procedures in the table, which must survive collections.
+    pub fn handles(&self) -> impl Iterator<Item = Handle> + '_ {
+        self.macros.values().chain(self.dispatch.values()).copied()
+    }
+}
@@ -0,0 +1,58 @@
+use crate::context::gc_heap::Handle;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Handles that host code wants kept alive, with how many `Root`s hold each.
+#[derive(Default)]
+pub struct RootSet {
+    counts: Rc<RefCell<HashMap<Handle, usize>>>,
+}
+
+impl RootSet {
+    pub fn root(&self, handle: Handle) -> Root {
+        *self.counts.borrow_mut().entry(handle).or_insert(0) += 1;
+        Root {
+            handle,
+            counts: self.counts.clone(),
+        }
+    }
+
+    pub fn handles(&self) -> Vec<Handle> {
+        self.counts.borrow().keys().copied().collect()
+    }
+}
+
+/// Keeps a handle alive across collections until it is dropped.
+pub struct Root {
+    handle: Handle,
+    counts: Rc<RefCell<HashMap<Handle, usize>>>,
+}
+
+impl Root {
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+}
+
+impl Clone for Root {
+    fn clone(&self) -> Self {
+        *self.counts.borrow_mut().entry(self.handle).or_insert(0) += 1;
+        Self {
+            handle: self.handle,
+            counts: self.counts.clone(),
+        }
+    }
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        let mut counts = self.counts.borrow_mut();
+        if let Some(count) = counts.get_mut(&self.handle) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::gc_heap::{GcHeap, Handle};
+
+/// A piece of source text with its name and the byte offset each line starts
+/// at, so positions can be turned into lines and columns without rescanning
+/// the text.
+#[derive(Debug)]
+pub struct SourceFile {
+    name: String,
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, text: String) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        Self {
+            name: name.to_string(),
+            text,
+            line_starts,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The 1-based line and column of the byte offset `pos`. Columns count
+    /// characters, not bytes.
+    pub fn line_column(&self, pos: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|start| *start <= pos) - 1;
+        let column = self.text[self.line_starts[line]..pos].chars().count();
+        (line + 1, column + 1)
+    }
+
+    /// The text of the 1-based `line`, without its line break.
+    pub fn line_text(&self, line: usize) -> &str {
+        let start = self.line_starts[line - 1];
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.text.len(), |next| next - 1);
+        self.text[start..end].trim_end_matches('\r')
+    }
+}
+
+/// The bytes `start..end` of a source file.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub source: Rc<SourceFile>,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(source: &Rc<SourceFile>, start: usize, end: usize) -> Self {
+        Self {
+            source: source.clone(),
+            start,
+            end,
+        }
+    }
+
+    pub fn start_line_column(&self) -> (usize, usize) {
+        self.source.line_column(self.start)
+    }
+
+    pub fn end_line_column(&self) -> (usize, usize) {
+        self.source.line_column(self.end)
+    }
+}
+
+/// Prints `file:line:column-line:column`.
+impl std::fmt::Display for Span {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (line, column) = self.start_line_column();
+        let (end_line, end_column) = self.end_line_column();
+        write!(
+            fmt,
+            "{}:{}:{}-{}:{}",
+            self.source.name(),
+            line,
+            column,
+            end_line,
+            end_column
+        )
+    }
+}
+
+/// Where the parser read each of the pairs it allocated. Entries don't keep
+/// their cells alive and are dropped once the cells are collected.
+#[derive(Default)]
+pub struct SpanTable {
+    spans: HashMap<Handle, Span>,
+}
+
+impl SpanTable {
+    pub fn insert(&mut self, handle: Handle, span: Span) {
+        self.spans.insert(handle, span);
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&Span> {
+        self.spans.get(&handle)
+    }
+
+    /// Drops the spans of cells that have been freed.
+    pub fn prune(&mut self, heap: &GcHeap) {
+        self.spans.retain(|h, _| heap.try_get_ref(*h).is_ok());
+    }
+}
@@ -0,0 +1,296 @@
+//! A lossless concrete syntax tree, for tools that rewrite source text.
+//!
+//! The tree keeps every token with its original spelling and the whitespace
+//! and comments before it, so printing it gives back the input byte for byte.
+//! Tokens are lexed by the same `Lexer` as the parser's, with an empty
+//! readtable since reader macros can only be run by evaluating code.
+
+use crate::context::readtable::Readtable;
+use crate::lexer::{Lexer, Token, TokenType, TriviaType};
+use crate::parser::{Note, ParseError, ParseErrorType};
+
+/// Whitespace or a comment, as written.
+#[derive(Debug)]
+pub struct CstTrivia<'a> {
+    pub r#type: TriviaType,
+    pub pos: usize,
+    pub text: &'a str,
+}
+
+/// A token as written, with the trivia in front of it.
+#[derive(Debug)]
+pub struct CstToken<'a> {
+    pub r#type: TokenType,
+    pub pos: usize,
+    pub text: &'a str,
+    pub leading: Vec<CstTrivia<'a>>,
+}
+
+impl<'a> CstToken<'a> {
+    /// The byte offset just past the token.
+    pub fn end(&self) -> usize {
+        self.pos + self.text.len()
+    }
+}
+
+#[derive(Debug)]
+pub enum Node<'a> {
+    /// A token that is a datum on its own, or a `.` in a list.
+    Atom(CstToken<'a>),
+    /// A list, vector or hash table. `open` is `(`, `#(` or `#hash(`.
+    List {
+        open: CstToken<'a>,
+        items: Vec<Node<'a>>,
+        close: CstToken<'a>,
+    },
+    /// A datum after `'`, `` ` ``, `,`, `,@`, a `#;` datum comment or a `#n=`
+    /// label.
+    Prefixed {
+        prefix: CstToken<'a>,
+        datum: Box<Node<'a>>,
+    },
+}
+
+impl<'a> Node<'a> {
+    /// The first token of the node, which holds the trivia in front of it.
+    pub fn first(&self) -> &CstToken<'a> {
+        match self {
+            Node::Atom(t) => t,
+            Node::List { open, .. } => open,
+            Node::Prefixed { prefix, .. } => prefix,
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            Node::Atom(t) => write_token(t, out),
+            Node::List { open, items, close } => {
+                write_token(open, out);
+                for item in items {
+                    item.write(out);
+                }
+                write_token(close, out);
+            }
+            Node::Prefixed { prefix, datum } => {
+                write_token(prefix, out);
+                datum.write(out);
+            }
+        }
+    }
+}
+
+fn write_token(t: &CstToken, out: &mut String) {
+    for trivia in &t.leading {
+        out.push_str(trivia.text);
+    }
+    out.push_str(t.text);
+}
+
+/// The top-level forms of a source and the trivia after the last one.
+#[derive(Debug)]
+pub struct Cst<'a> {
+    pub forms: Vec<Node<'a>>,
+    pub trailing: Vec<CstTrivia<'a>>,
+}
+
+impl<'a> Cst<'a> {
+    pub fn parse(source: &'a str) -> Result<Cst<'a>, ParseError> {
+        let mut builder = Builder {
+            lexer: Lexer::new(source),
+            source,
+            readtable: Readtable::default(),
+            trailing: vec![],
+        };
+        let mut forms = vec![];
+        while let Some(t) = builder.token()? {
+            forms.push(builder.node(t)?);
+        }
+        Ok(Cst {
+            forms,
+            trailing: builder.trailing,
+        })
+    }
+
+    /// An outline of the tree, one token or piece of trivia per line, for
+    /// debugging tools built on it.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for form in &self.forms {
+            dump_node(form, 0, &mut out);
+        }
+        dump_trivia(&self.trailing, 0, &mut out);
+        out
+    }
+}
+
+fn dump_trivia(trivia: &[CstTrivia], depth: usize, out: &mut String) {
+    for t in trivia {
+        out.push_str(&format!(
+            "{}{:?} {}..{} {:?}\n",
+            "  ".repeat(depth),
+            t.r#type,
+            t.pos,
+            t.pos + t.text.len(),
+            t.text
+        ));
+    }
+}
+
+fn dump_token(t: &CstToken, depth: usize, out: &mut String) {
+    dump_trivia(&t.leading, depth, out);
+    out.push_str(&format!(
+        "{}{:?} {}..{} {:?}\n",
+        "  ".repeat(depth),
+        t.r#type,
+        t.pos,
+        t.end(),
+        t.text
+    ));
+}
+
+fn dump_node(node: &Node, depth: usize, out: &mut String) {
+    match node {
+        Node::Atom(t) => dump_token(t, depth, out),
+        Node::List { open, items, close } => {
+            dump_token(open, depth, out);
+            for item in items {
+                dump_node(item, depth + 1, out);
+            }
+            dump_token(close, depth, out);
+        }
+        Node::Prefixed { prefix, datum } => {
+            dump_token(prefix, depth, out);
+            dump_node(datum, depth + 1, out);
+        }
+    }
+}
+
+/// Prints the source the tree was parsed from.
+impl<'a> std::fmt::Display for Cst<'a> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut out = String::new();
+        for form in &self.forms {
+            form.write(&mut out);
+        }
+        for trivia in &self.trailing {
+            out.push_str(trivia.text);
+        }
+        write!(fmt, "{}", out)
+    }
+}
+
+struct Builder<'a> {
+    lexer: Lexer<'a>,
+    source: &'a str,
+    readtable: Readtable,
+    /// The trivia after the last token, once the end is reached.
+    trailing: Vec<CstTrivia<'a>>,
+}
+
+impl<'a> Builder<'a> {
+    /// The next token with the trivia in front of it.
+    fn token(&mut self) -> Result<Option<CstToken<'a>>, ParseError> {
+        let mut leading = vec![];
+        while let Some(t) = self.lexer.next_trivia()? {
+            leading.push(CstTrivia {
+                r#type: t.r#type,
+                pos: t.pos,
+                text: &self.source[t.pos..t.end],
+            });
+        }
+        match self.lexer.next_token(&self.readtable)? {
+            Some(Token { r#type, pos, .. }) => Ok(Some(CstToken {
+                r#type,
+                pos,
+                text: &self.source[pos..self.lexer.pos()],
+                leading,
+            })),
+            None => {
+                self.trailing = leading;
+                Ok(None)
+            }
+        }
+    }
+
+    fn eof_error(&self) -> ParseError {
+        let end = self.source.len();
+        ParseError::new(ParseErrorType::UnexpectedEOF, end, end)
+    }
+
+    /// The node that starts with `first`.
+    fn node(&mut self, first: CstToken<'a>) -> Result<Node<'a>, ParseError> {
+        match first.r#type {
+            TokenType::LPAREN | TokenType::VECTOR_START | TokenType::HASH_TABLE_START => {
+                let mut items = vec![];
+                loop {
+                    let t = match self.token()? {
+                        Some(t) => t,
+                        None => {
+                            let mut err = self.eof_error();
+                            err.notes.push(Note {
+                                pos: first.pos,
+                                end: first.end(),
+                                message: format!("unclosed `{}` opened here", first.text),
+                            });
+                            return Err(err);
+                        }
+                    };
+                    if t.r#type == TokenType::RPAREN {
+                        return Ok(Node::List {
+                            open: first,
+                            items,
+                            close: t,
+                        });
+                    }
+                    items.push(self.node(t)?);
+                }
+            }
+            TokenType::RPAREN => Err(ParseError::new(
+                ParseErrorType::UnexpectedRPAREN,
+                first.pos,
+                first.end(),
+            )),
+            TokenType::QUOTE
+            | TokenType::QUASIQUOTE
+            | TokenType::UNQUOTE
+            | TokenType::UNQUOTE_SPLICING
+            | TokenType::DATUM_COMMENT
+            | TokenType::LABEL_DEF => match self.token()? {
+                Some(t) => Ok(Node::Prefixed {
+                    prefix: first,
+                    datum: Box::new(self.node(t)?),
+                }),
+                None => Err(self.eof_error()),
+            },
+            _ => Ok(Node::Atom(first)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(source: &str) {
+        let cst = Cst::parse(source).unwrap();
+        assert_eq!(cst.to_string(), source);
+    }
+
+    #[test]
+    fn round_trips_trivia() {
+        round_trip("#!/usr/bin/env maxlisp\n(def a 1) ; trailing\n");
+        round_trip("#| outer #| inner |# still outer |#\n(a #| mid |# b)\n");
+        round_trip("(a #;(b c) #; d e)\n#;#;x y\n");
+        round_trip("(a\r\n  b)\r\n; comment\r\n");
+        round_trip("   ");
+    }
+
+    #[test]
+    fn round_trips_atoms() {
+        round_trip(r#"("a\"b\\c\n\t" "\x41;\u{3bb}" "λ")"#);
+        round_trip(r"(#\( #\) #\space #\x41 #\;)");
+        round_trip("(#0=(a . #0#) #1=#(1 2) #1#)");
+        round_trip("#hash((a . 1) (b . 2)) #hasheqv() #hasheq((1 . 2))");
+        round_trip("'(a `(b ,c ,@d)) #(1 2.5 1/2 #xff)");
+    }
+}
@@ -2,11 +2,16 @@ pub mod env;
 use std::collections::VecDeque;
 
 use crate::{
-    context::{Context, gc_heap::Handle},
-    sexp::{BuiltinFn, Sexp, Symbol},
+    context::{
+        Context,
+        gc_heap::{Handle, HeapError},
+    },
+    sexp::{
+        BuiltinFn, Sexp, Symbol,
+        printer::{PrintMode, PrintOptions, print},
+    },
 };
 use builtins::global_env;
-use env::Env;
 pub mod builtins;
 
 #[derive(Debug)]
@@ -18,8 +23,48 @@ pub enum EvalError {
     SymbolNotBound(String),
     CannotPopGlobalEnv,
     InvalidNumberOfArguments,
+    OutOfMemory,
+    DivisionByZero,
+    IndexOutOfRange(i64, usize),
+    ReadError(String),
+    Heap(HeapError),
 }
 
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::StackUnderflow => write!(fmt, "stack underflow"),
+            Self::QueueUnderflow => write!(fmt, "queue underflow"),
+            Self::CantPushOperator => write!(fmt, "can't push an operator"),
+            Self::TypeError(msg) => write!(fmt, "type error: {}", msg),
+            Self::SymbolNotBound(sym) => write!(fmt, "symbol not bound: {}", sym),
+            Self::CannotPopGlobalEnv => write!(fmt, "cannot pop the global environment"),
+            Self::InvalidNumberOfArguments => write!(fmt, "invalid number of arguments"),
+            Self::OutOfMemory => write!(fmt, "out of memory"),
+            Self::DivisionByZero => write!(fmt, "division by zero"),
+            Self::IndexOutOfRange(index, len) => {
+                write!(fmt, "index {} out of range for length {}", index, len)
+            }
+            Self::ReadError(msg) => write!(fmt, "read error: {}", msg),
+            Self::Heap(e) => write!(fmt, "heap error: {}", e),
+        }
+    }
+}
+
+impl From<HeapError> for EvalError {
+    fn from(e: HeapError) -> Self {
+        Self::Heap(e)
+    }
+}
+
+/// How values are printed in the trace of each step, kept short so large
+/// data doesn't drown it.
+const TRACE_PRINT: PrintOptions = PrintOptions {
+    mode: PrintMode::Write,
+    max_depth: Some(8),
+    max_length: Some(16),
+};
+
 pub enum EvalItem {
     Operator(BuiltinFn, &'static str),
     Operand(Handle),
@@ -29,7 +74,7 @@ impl EvalItem {
     pub fn to_string(&self, ctx: &Context) -> String {
         match self {
             Self::Operator(_, name) => format!("<op {}>", name),
-            Self::Operand(h) => ctx.heap.get_ref(*h).to_string(ctx),
+            Self::Operand(h) => print(*h, ctx, TRACE_PRINT),
         }
     }
 }
@@ -38,16 +83,25 @@ pub struct Evaluator {
     stack: Vec<EvalItem>,
     queue: VecDeque<EvalItem>,
     env_stack: Vec<Handle>,
-    nil: Handle,
+    /// The queues of computations waiting for a nested `call` to finish.
+    suspended: Vec<VecDeque<EvalItem>>,
 }
 
 impl Evaluator {
     pub fn new(ctx: &mut Context) -> Self {
+        let global = match ctx.global_env {
+            Some(env) => env,
+            None => {
+                let env = global_env(ctx);
+                ctx.global_env = Some(env);
+                env
+            }
+        };
         Self {
             stack: vec![],
             queue: VecDeque::new(),
-            env_stack: vec![global_env(ctx)],
-            nil: ctx.heap.alloc(Sexp::Nil),
+            env_stack: vec![global],
+            suspended: vec![],
         }
     }
 
@@ -77,8 +131,7 @@ impl Evaluator {
     }
 
     pub fn lookup(&self, sym: Symbol, ctx: &Context) -> Option<Handle> {
-        let env = ctx.heap.get_ref(self.get_env());
-        match env {
+        match ctx.heap.get_ref(self.get_env()).as_ref() {
             Sexp::Env(env) => env.lookup(sym, ctx),
             _ => unreachable!(),
         }
@@ -97,8 +150,8 @@ impl Evaluator {
         }
     }
 
-    pub fn get_env(self: &Self) -> Handle {
-        return *self.env_stack.last().unwrap();
+    pub fn get_env(&self) -> Handle {
+        *self.env_stack.last().unwrap()
     }
 
     pub fn define(&self, sym: Symbol, val: Handle, ctx: &mut Context) {
@@ -110,25 +163,117 @@ impl Evaluator {
     }
 
     pub fn get_nil(&self) -> Handle {
-        return self.nil;
+        Handle::Nil
+    }
+
+    fn roots(&self, ctx: &Context) -> Vec<Handle> {
+        let operands = self
+            .stack
+            .iter()
+            .chain(self.queue.iter())
+            .chain(self.suspended.iter().flatten())
+            .filter_map(|item| match item {
+                EvalItem::Operand(h) => Some(*h),
+                EvalItem::Operator(_, _) => None,
+            });
+        operands
+            .chain(self.env_stack.iter().copied())
+            .chain(ctx.roots.handles())
+            .chain(ctx.readtable.handles())
+            .collect()
+    }
+
+    /// Collects every cell that is not reachable from the stack, the queues,
+    /// the environment stack, the context's roots or the readtable. Debug
+    /// builds verify the heap afterwards.
+    pub fn collect(&self, ctx: &mut Context) {
+        let roots = self.roots(ctx);
+        ctx.heap.collect(roots);
+        ctx.spans.prune(&ctx.heap);
+        if cfg!(debug_assertions)
+            && let Err(e) = self.verify_heap(ctx)
+        {
+            panic!("heap corrupted after collection: {}", e);
+        }
     }
 
+    pub fn verify_heap(&self, ctx: &Context) -> Result<usize, HeapError> {
+        ctx.heap.verify(self.roots(ctx))
+    }
+
+    /// Drops whatever is left of the current computation and goes back to
+    /// the global environment.
+    pub fn reset(&mut self, ctx: &mut Context) {
+        self.stack.clear();
+        self.queue.clear();
+        self.suspended.clear();
+        self.env_stack.truncate(1);
+        self.collect(ctx);
+    }
+
+    fn step(&mut self, ctx: &mut Context) -> Result<bool, EvalError> {
+        if ctx.heap.needs_collection() {
+            self.collect(ctx);
+            if ctx.heap.is_exhausted() {
+                return Err(EvalError::OutOfMemory);
+            }
+        }
+        match self.queue.pop_front() {
+            Some(EvalItem::Operator(op, _)) => op(self, ctx)?,
+            Some(EvalItem::Operand(h)) => self.push(h),
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Runs the queue until it is empty. On error the evaluator is reset, so
+    /// it can be used again for the next top-level form.
     pub fn run(&mut self, ctx: &mut Context) -> Result<(), EvalError> {
         loop {
-            let item = self.queue.pop_front();
-            match item {
-                Some(item) => match item {
-                    EvalItem::Operator(op, _) => op(self, ctx)?,
-                    EvalItem::Operand(h) => self.push(h),
-                },
-                None => break,
+            match self.step(ctx) {
+                Ok(true) => println!("{}", self.to_string(ctx)),
+                Ok(false) => break,
+                Err(e) => {
+                    self.reset(ctx);
+                    return Err(e);
+                }
             }
-            println!("{}", self.to_string(ctx));
         }
         println!("{}", self.to_string(ctx));
         Ok(())
     }
 
+    /// Applies the combiner `f` to `args`, a list of values, and runs it to
+    /// completion. Whatever the evaluator was doing is suspended meanwhile,
+    /// so host code such as the parser can call Lisp procedures, even from
+    /// within a builtin.
+    pub fn call(
+        &mut self,
+        f: Handle,
+        args: Handle,
+        ctx: &mut Context,
+    ) -> Result<Handle, EvalError> {
+        let f = builtins::combiner(f, ctx)?;
+        let stack_depth = self.stack.len();
+        let env_depth = self.env_stack.len();
+        self.suspended.push(std::mem::take(&mut self.queue));
+        self.queue.push_back(EvalItem::Operand(f));
+        self.queue.push_back(EvalItem::Operand(args));
+        self.queue
+            .push_back(EvalItem::Operator(builtins::apply, "apply"));
+        let result = loop {
+            match self.step(ctx) {
+                Ok(true) => {}
+                Ok(false) => break self.pop(),
+                Err(e) => break Err(e),
+            }
+        };
+        self.queue = self.suspended.pop().unwrap();
+        self.stack.truncate(stack_depth);
+        self.env_stack.truncate(env_depth);
+        result
+    }
+
     pub fn to_string(&self, ctx: &Context) -> String {
         let mut result = String::from("[");
         for (i, item) in self.stack.iter().enumerate() {
@@ -148,3 +293,55 @@ impl Evaluator {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextOptions;
+    use crate::context::spans::SourceFile;
+    use crate::parser::Parser;
+    use std::rc::Rc;
+
+    /// Evaluates every form in `text` and returns the value of the last one
+    /// and the number of cells allocated while evaluating, not reading, it.
+    fn run(text: &str) -> (Handle, usize) {
+        let mut ctx = Context::new(ContextOptions::default());
+        let mut e = Evaluator::new(&mut ctx);
+        let source = Rc::new(SourceFile::new("test", text.to_string()));
+        let mut parser = Parser::new(&source);
+        let mut result = (Handle::Nil, 0);
+        while let Some(form) = parser.next_form(&mut ctx, &mut e).unwrap() {
+            let form = ctx.root(form);
+            let before = ctx.heap.allocations();
+            e.push_back(EvalItem::Operand(form.handle()));
+            e.push_back(EvalItem::Operator(builtins::eval, "eval"));
+            e.run(&mut ctx).unwrap();
+            let value = match e.stack.pop() {
+                Some(EvalItem::Operand(h)) => h,
+                _ => Handle::Inert,
+            };
+            result = (value, ctx.heap.allocations() - before);
+        }
+        result
+    }
+
+    #[test]
+    fn integer_arithmetic_allocates_no_cells_for_integers() {
+        let few = run("(add 1 2)");
+        let args: Vec<String> = (1..=500).map(|i| i.to_string()).collect();
+        let many = run(&format!("(add {})", args.join(" ")));
+        assert_eq!(many.0, Handle::Integer(125250));
+        assert_eq!(many.1, few.1);
+    }
+
+    #[test]
+    fn environments_evaluate_to_themselves() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let mut e = Evaluator::new(&mut ctx);
+        let env = e.get_env();
+        e.push_back(EvalItem::Operand(env));
+        e.push_back(EvalItem::Operator(builtins::eval, "eval"));
+        e.run(&mut ctx).unwrap();
+        assert!(matches!(e.stack.pop(), Some(EvalItem::Operand(h)) if h == env));
+    }
+}
@@ -1,36 +1,133 @@
 use std::collections::VecDeque;
 
+use crate::context::gc_heap::GcHeap;
 use crate::context::{Context, gc_heap::Handle};
 use crate::evaluator::Evaluator;
 use crate::evaluator::env::Env;
-use crate::sexp::{Closure, Sexp};
+use crate::number::Number;
+use crate::parser::Parser;
+use crate::sexp::hash_table::{HashTable, TableKind};
+use crate::sexp::port::Port;
+use crate::sexp::printer::{PrintMode, PrintOptions, print};
+use crate::sexp::{BuiltinFn, Closure, Sexp};
 
 use super::{EvalError, EvalItem};
 
+fn numbers(args_h: Handle, ctx: &Context) -> Result<Vec<Number>, EvalError> {
+    let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
+    args.into_iter()
+        .map(|a| {
+            Number::from_handle(a, ctx)
+                .map_err(|_| EvalError::TypeError(String::from("expected a list of numbers")))
+        })
+        .collect()
+}
+
 pub fn add(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
-    let args_h = e.pop()?;
-    let args = ctx.heap.get_ref(args_h).into_list(ctx)?;
-    let mut result: i64 = 0;
+    let args = numbers(e.pop()?, ctx)?;
+    let mut result = Number::Integer(0);
     for a in args {
-        result += a
-            .into_integer(ctx)
-            .map_err(|_| EvalError::TypeError(String::from("expected a list of integers")))?;
+        result = result.add(&a);
     }
-    e.push(ctx.heap.alloc(Sexp::Integer(result)));
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+/// `(sub x)` negates `x`, `(sub x y ...)` subtracts the rest from `x`.
+pub fn sub(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = numbers(e.pop()?, ctx)?;
+    let result = match args.as_slice() {
+        [] => return Err(EvalError::InvalidNumberOfArguments),
+        [x] => Number::Integer(0).sub(x),
+        [x, rest @ ..] => {
+            let mut result = x.clone();
+            for a in rest {
+                result = result.sub(a);
+            }
+            result
+        }
+    };
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+pub fn mul(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = numbers(e.pop()?, ctx)?;
+    let mut result = Number::Integer(1);
+    for a in args {
+        result = result.mul(&a);
+    }
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+/// `(div x)` is the reciprocal of `x`, `(div x y ...)` divides `x` by the rest.
+pub fn div(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = numbers(e.pop()?, ctx)?;
+    let result = match args.as_slice() {
+        [] => return Err(EvalError::InvalidNumberOfArguments),
+        [x] => Number::Integer(1).div(x)?,
+        [x, rest @ ..] => {
+            let mut result = x.clone();
+            for a in rest {
+                result = result.div(a)?;
+            }
+            result
+        }
+    };
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+/// Pops the argument list of a builtin that takes a single number.
+fn number_arg(e: &mut Evaluator, ctx: &Context) -> Result<Number, EvalError> {
+    match numbers(e.pop()?, ctx)?.as_slice() {
+        [x] => Ok(x.clone()),
+        _ => Err(EvalError::InvalidNumberOfArguments),
+    }
+}
+
+pub fn numerator(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let result = number_arg(e, ctx)?.numerator()?;
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+pub fn denominator(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let result = number_arg(e, ctx)?.denominator()?;
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+pub fn floor(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let result = number_arg(e, ctx)?.floor();
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+pub fn round(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let result = number_arg(e, ctx)?.round();
+    e.push(result.into_handle(ctx));
+    Ok(())
+}
+
+pub fn exact_to_inexact(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let result = Number::Real(number_arg(e, ctx)?.to_real());
+    e.push(result.into_handle(ctx));
     Ok(())
 }
 
 pub fn vau(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args_h = e.pop()?;
-    let args = ctx.heap.get_ref(args_h).into_handle_list(ctx)?;
+    let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
     if args.len() != 3 {
         return Err(EvalError::InvalidNumberOfArguments);
     }
 
-    let vars = ctx.heap.get_ref(args[0]).into_handle_list(ctx)?;
+    let vars = ctx.heap.try_get_ref(args[0])?.to_handle_list(ctx)?;
 
     let sym = args[1];
-    match ctx.heap.get_ref(sym) {
+    match ctx.heap.try_get_ref(sym)?.as_ref() {
         Sexp::Symbol(_) => (),
         _ => {
             return Err(EvalError::TypeError(String::from(
@@ -43,9 +140,9 @@ pub fn vau(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
 
     let closure = Closure {
         env: e.get_env(),
-        vars: vars,
-        sym: sym,
-        body: body,
+        vars,
+        sym,
+        body,
     };
     e.push(ctx.heap.alloc(Sexp::Closure(closure)));
     Ok(())
@@ -53,11 +150,11 @@ pub fn vau(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
 
 pub fn def_raw(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args_h = e.pop()?;
-    let args = ctx.heap.get_ref(args_h).into_handle_list(ctx)?;
+    let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
     if args.len() != 2 {
         return Err(EvalError::InvalidNumberOfArguments);
     }
-    let sym = match ctx.heap.get_ref(args[0]) {
+    let sym = match ctx.heap.try_get_ref(args[0])?.as_ref() {
         Sexp::Symbol(sym) => *sym,
         _ => return Err(EvalError::TypeError(String::from("expected symbol"))),
     };
@@ -68,7 +165,7 @@ pub fn def_raw(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
 
 pub fn def(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args_h = e.pop()?;
-    let args = ctx.heap.get_ref(args_h).into_handle_list(ctx)?;
+    let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
     if args.len() != 2 {
         return Err(EvalError::InvalidNumberOfArguments);
     }
@@ -95,7 +192,7 @@ pub fn cons(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
 }
 
 pub fn wrap_helper(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
-    let args = ctx.heap.get_ref(e.pop()?).into_handle_list(ctx)?;
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
     if args.len() != 1 {
         return Err(EvalError::InvalidNumberOfArguments);
     }
@@ -105,7 +202,7 @@ pub fn wrap_helper(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError
 }
 
 pub fn wrap(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
-    let args = ctx.heap.get_ref(e.pop()?).into_handle_list(ctx)?;
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
     if args.len() != 1 {
         return Err(EvalError::InvalidNumberOfArguments);
     }
@@ -115,7 +212,7 @@ pub fn wrap(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
         EvalItem::Operator(eval, "eval"),
         EvalItem::Operand(e.get_nil()),
         EvalItem::Operator(cons, "cons"),
-        EvalItem::Operator(wrap_helper, "wrap_helper")
+        EvalItem::Operator(wrap_helper, "wrap_helper"),
     ]);
     e.push_front(q);
     Ok(())
@@ -123,17 +220,19 @@ pub fn wrap(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
 
 pub fn eval(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let h = e.pop()?;
-    let sexp = ctx.heap.get_ref(h);
-    match sexp {
+    match ctx.heap.try_get_ref(h)?.as_ref() {
         Sexp::Integer(_) => e.push(h),
+        Sexp::BigInt(_) => e.push(h),
+        Sexp::Rational(_) => e.push(h),
+        Sexp::Real(_) => e.push(h),
         Sexp::Symbol(sym) => match e.lookup(*sym, ctx) {
             Some(h) => e.push(h),
             None => {
                 return Err(EvalError::SymbolNotBound(
                     ctx.interner
                         .string_from_symbol(*sym)
-                        .unwrap_or(&String::from("??"))
-                        .clone(),
+                        .unwrap_or("??")
+                        .to_string(),
                 ));
             }
         },
@@ -147,9 +246,13 @@ pub fn eval(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
             q.push_back(EvalItem::Operator(apply, "apply"));
             e.push_front(q);
         }
-        Sexp::Nil => todo!(),
-        Sexp::Env(_) => todo!(),
-        Sexp::Builtin(_) => e.push(h),
+        Sexp::Vector(_) => e.push(h),
+        Sexp::HashTable(_) => e.push(h),
+        Sexp::Port(_) => e.push(h),
+        Sexp::Nil => e.push(h),
+        Sexp::Boolean(_) | Sexp::Char(_) | Sexp::Inert | Sexp::Ignore => e.push(h),
+        Sexp::Env(_) => e.push(h),
+        Sexp::Builtin(_, _) => e.push(h),
         Sexp::Closure(_) => e.push(h),
         Sexp::WrappedProc(_) => e.push(h),
     }
@@ -168,7 +271,7 @@ pub fn push(e: &mut Evaluator, _: &mut Context) -> Result<(), EvalError> {
 
 pub fn push_env(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args_h = e.pop()?;
-    let args = ctx.heap.get_ref(args_h).into_handle_list(ctx)?;
+    let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
     if args.len() != 1 {
         return Err(EvalError::InvalidNumberOfArguments);
     }
@@ -184,20 +287,20 @@ pub fn pop_env(e: &mut Evaluator, _: &mut Context) -> Result<(), EvalError> {
 pub fn apply(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args_h = e.pop()?;
     let proc_h = e.pop()?;
-    let proc = ctx.heap.get_ref(proc_h);
+    let proc = ctx.heap.try_get_ref(proc_h)?;
     let mut q = VecDeque::new();
-    match proc {
-        Sexp::Builtin(func) => {
+    match proc.as_ref() {
+        Sexp::Builtin(func, name) => {
             q.push_back(EvalItem::Operand(args_h));
-            q.push_back(EvalItem::Operator(*func, "builtin func"));
+            q.push_back(EvalItem::Operator(*func, name));
         }
         Sexp::Closure(c) => {
-            let args = ctx.heap.get_ref(args_h).into_handle_list(ctx)?;
+            let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
             if c.vars.len() != args.len() {
                 return Err(EvalError::InvalidNumberOfArguments);
             }
             // TODO: the bug is probably here : create a new environment and set the outer to be c.env
-            let new_bindings: Vec<(usize, usize)> = c.vars.iter().map(|v| *v).zip(args).collect();
+            let new_bindings: Vec<(Handle, Handle)> = c.vars.iter().copied().zip(args).collect();
             let sym = c.sym;
             let body = c.body;
             let new_env = ctx.heap.alloc(Sexp::Env(Env::new(Some(c.env))));
@@ -225,7 +328,7 @@ pub fn apply(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
         }
         Sexp::WrappedProc(p) => {
             q.push_back(EvalItem::Operand(*p));
-            let args = ctx.heap.get_ref(args_h).into_handle_list(ctx)?;
+            let args = ctx.heap.try_get_ref(args_h)?.to_handle_list(ctx)?;
             for arg in &args {
                 q.push_back(EvalItem::Operand(*arg));
                 q.push_back(EvalItem::Operator(eval, "eval"));
@@ -242,9 +345,118 @@ pub fn apply(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     Ok(())
 }
 
+pub fn quote(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    if args.len() != 1 {
+        return Err(EvalError::InvalidNumberOfArguments);
+    }
+    e.push(args[0]);
+    Ok(())
+}
+
+/// Pops a tail and a list and pushes a copy of the list ending in the tail.
+/// Used to splice `,@` forms into a quasiquoted list.
+pub fn append(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let tail = e.pop()?;
+    let list = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    let mut result = tail;
+    for h in list.iter().rev() {
+        result = ctx.heap.alloc(Sexp::Pair(*h, result));
+    }
+    e.push(result);
+    Ok(())
+}
+
+/// If `h` is a two element list headed by `name`, returns its second element.
+fn unwrap_form(h: Handle, name: &str, ctx: &mut Context) -> Result<Option<Handle>, EvalError> {
+    let name = Handle::Symbol(ctx.interner.intern(name));
+    match ctx.heap.try_get_ref(h)?.as_ref() {
+        Sexp::Pair(car, cdr) if *car == name => match ctx.heap.try_get_ref(*cdr)?.as_ref() {
+            Sexp::Pair(x, Handle::Nil) => Ok(Some(*x)),
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Queues the work that builds `template` on the stack. `depth` counts the
+/// enclosing quasiquotes: only unquotes at depth 1 are evaluated, deeper ones
+/// are rebuilt with their depth lowered by one.
+fn quasi(
+    template: Handle,
+    depth: usize,
+    q: &mut VecDeque<EvalItem>,
+    ctx: &mut Context,
+) -> Result<(), EvalError> {
+    for (name, inner_depth) in [
+        ("unquote", depth - 1),
+        ("unquote-splicing", depth - 1),
+        ("quasiquote", depth + 1),
+    ] {
+        if let Some(x) = unwrap_form(template, name, ctx)? {
+            if inner_depth == 0 {
+                if name == "unquote-splicing" {
+                    return Err(EvalError::TypeError(String::from(
+                        "unquote-splicing outside of a list",
+                    )));
+                }
+                q.push_back(EvalItem::Operand(x));
+                q.push_back(EvalItem::Operator(eval, "eval"));
+            } else {
+                q.push_back(EvalItem::Operand(Handle::Symbol(ctx.interner.intern(name))));
+                quasi(x, inner_depth, q, ctx)?;
+                q.push_back(EvalItem::Operand(Handle::Nil));
+                q.push_back(EvalItem::Operator(cons, "cons"));
+                q.push_back(EvalItem::Operator(cons, "cons"));
+            }
+            return Ok(());
+        }
+    }
+    let (car, cdr) = match ctx.heap.try_get_ref(template)?.as_ref() {
+        Sexp::Pair(car, cdr) => (*car, *cdr),
+        Sexp::Vector(v) => {
+            let list = Sexp::from_handle_list(v.clone(), ctx);
+            quasi(list, depth, q, ctx)?;
+            q.push_back(EvalItem::Operand(Handle::Nil));
+            q.push_back(EvalItem::Operator(cons, "cons"));
+            q.push_back(EvalItem::Operator(list_to_vector, "list->vector"));
+            return Ok(());
+        }
+        _ => {
+            q.push_back(EvalItem::Operand(template));
+            return Ok(());
+        }
+    };
+    match unwrap_form(car, "unquote-splicing", ctx)? {
+        Some(x) if depth == 1 => {
+            q.push_back(EvalItem::Operand(x));
+            q.push_back(EvalItem::Operator(eval, "eval"));
+            quasi(cdr, depth, q, ctx)?;
+            q.push_back(EvalItem::Operator(append, "append"));
+        }
+        _ => {
+            quasi(car, depth, q, ctx)?;
+            quasi(cdr, depth, q, ctx)?;
+            q.push_back(EvalItem::Operator(cons, "cons"));
+        }
+    }
+    Ok(())
+}
+
+pub fn quasiquote(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    if args.len() != 1 {
+        return Err(EvalError::InvalidNumberOfArguments);
+    }
+    let mut q = VecDeque::new();
+    quasi(args[0], 1, &mut q, ctx)?;
+    e.push_front(q);
+    Ok(())
+}
+
 pub fn car(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args = e.pop()?;
-    match ctx.heap.get_ref(args) {
+    match ctx.heap.try_get_ref(args)?.as_ref() {
         Sexp::Pair(car, _) => {
             e.push(*car);
             Ok(())
@@ -258,7 +470,7 @@ pub fn car(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
 
 pub fn cdr(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     let args = e.pop()?;
-    match ctx.heap.get_ref(args) {
+    match ctx.heap.try_get_ref(args)?.as_ref() {
         Sexp::Pair(_, cdr) => {
             e.push(*cdr);
             Ok(())
@@ -270,35 +482,460 @@ pub fn cdr(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
     }
 }
 
-pub fn global_env(ctx: &mut Context) -> Handle {
-    let mut env = Env::new(None);
-    env.def(
-        ctx.interner.intern("add"),
-        ctx.heap.alloc(Sexp::Builtin(add)),
-    );
-    env.def(
-        ctx.interner.intern("eval"),
-        ctx.heap.alloc(Sexp::Builtin(eval)),
-    );
-    env.def(
-        ctx.interner.intern("vau"),
-        ctx.heap.alloc(Sexp::Builtin(vau)),
-    );
-    env.def(
-        ctx.interner.intern("def"),
-        ctx.heap.alloc(Sexp::Builtin(def)),
-    );
-    env.def(
-        ctx.interner.intern("wrap"),
-        ctx.heap.alloc(Sexp::Builtin(wrap)),
-    );
-    env.def(
-        ctx.interner.intern("car"),
-        ctx.heap.alloc(Sexp::Builtin(car)),
+pub fn gensym(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    if !args.is_empty() {
+        return Err(EvalError::InvalidNumberOfArguments);
+    }
+    e.push(Handle::Symbol(ctx.interner.gensym()));
+    Ok(())
+}
+
+pub fn make_symbol(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    if args.len() != 1 {
+        return Err(EvalError::InvalidNumberOfArguments);
+    }
+    let name = match ctx.heap.try_get_ref(args[0])?.as_ref() {
+        Sexp::String(s) => s.clone(),
+        _ => return Err(EvalError::TypeError(String::from("expected a string"))),
+    };
+    e.push(Handle::Symbol(ctx.interner.make_symbol(&name)));
+    Ok(())
+}
+
+/// Pops the argument list of an applicative builtin, checking its length.
+fn args_n(e: &mut Evaluator, ctx: &Context, n: usize) -> Result<Vec<Handle>, EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    if args.len() != n {
+        return Err(EvalError::InvalidNumberOfArguments);
+    }
+    Ok(args)
+}
+
+fn vector_elements(h: Handle, ctx: &Context) -> Result<Vec<Handle>, EvalError> {
+    match ctx.heap.try_get_ref(h)?.as_ref() {
+        Sexp::Vector(v) => Ok(v.clone()),
+        _ => Err(EvalError::TypeError(String::from("expected a vector"))),
+    }
+}
+
+/// Checks that `h` is an integer index into a sequence of length `len`.
+fn index(h: Handle, len: usize) -> Result<usize, EvalError> {
+    match h {
+        Handle::Integer(i) if i >= 0 && (i as usize) < len => Ok(i as usize),
+        Handle::Integer(i) => Err(EvalError::IndexOutOfRange(i, len)),
+        _ => Err(EvalError::TypeError(String::from(
+            "expected an integer index",
+        ))),
+    }
+}
+
+/// The longest vector `make-vector` creates, so a single call can't take
+/// down the host whatever the heap limit.
+const MAX_VECTOR_LENGTH: usize = 1 << 24;
+
+/// `(make-vector k)` or `(make-vector k fill)`; the default fill is `()`.
+/// The vector takes a single cell however long it is, so `k` is bounded
+/// separately: by `MAX_VECTOR_LENGTH`, and by the heap limit if one is set.
+pub fn make_vector(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    let (k, fill) = match args.as_slice() {
+        [k] => (*k, Handle::Nil),
+        [k, fill] => (*k, *fill),
+        _ => return Err(EvalError::InvalidNumberOfArguments),
+    };
+    let k = match k {
+        Handle::Integer(k) if k >= 0 => k as usize,
+        _ => {
+            return Err(EvalError::TypeError(String::from(
+                "expected a non-negative length",
+            )));
+        }
+    };
+    if k > MAX_VECTOR_LENGTH || ctx.heap.max_cells().is_some_and(|max| k > max) {
+        return Err(EvalError::OutOfMemory);
+    }
+    let mut v = Vec::new();
+    v.try_reserve_exact(k).map_err(|_| EvalError::OutOfMemory)?;
+    v.resize(k, fill);
+    e.push(ctx.heap.alloc(Sexp::Vector(v)));
+    Ok(())
+}
+
+pub fn vector_ref(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 2)?;
+    let v = vector_elements(args[0], ctx)?;
+    e.push(v[index(args[1], v.len())?]);
+    Ok(())
+}
+
+pub fn vector_set(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 3)?;
+    let len = vector_elements(args[0], ctx)?.len();
+    let i = index(args[1], len)?;
+    if let Sexp::Vector(v) = ctx.heap.try_get_mut_ref(args[0])? {
+        v[i] = args[2];
+    }
+    e.push(Handle::Inert);
+    Ok(())
+}
+
+pub fn vector_length(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let len = vector_elements(args[0], ctx)?.len();
+    e.push(Handle::Integer(len as i64));
+    Ok(())
+}
+
+pub fn vector_to_list(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let v = vector_elements(args[0], ctx)?;
+    e.push(Sexp::from_handle_list(v, ctx));
+    Ok(())
+}
+
+pub fn list_to_vector(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let v = ctx.heap.try_get_ref(args[0])?.to_handle_list(ctx)?;
+    e.push(ctx.heap.alloc(Sexp::Vector(v)));
+    Ok(())
+}
+
+/// The combiner to apply to arguments that are already values: an
+/// applicative is called through its underlying combiner so they are not
+/// evaluated again.
+pub fn combiner(h: Handle, ctx: &Context) -> Result<Handle, EvalError> {
+    match ctx.heap.try_get_ref(h)?.as_ref() {
+        Sexp::WrappedProc(p) => Ok(*p),
+        Sexp::Builtin(_, _) | Sexp::Closure(_) => Ok(h),
+        _ => Err(EvalError::TypeError(String::from("expected a procedure"))),
+    }
+}
+
+/// Queues a call of combiner `f` on the values `args`.
+fn queue_call(f: Handle, args: Vec<Handle>, q: &mut VecDeque<EvalItem>, ctx: &mut Context) {
+    q.push_back(EvalItem::Operand(f));
+    q.push_back(EvalItem::Operand(Sexp::from_handle_list(args, ctx)));
+    q.push_back(EvalItem::Operator(apply, "apply"));
+}
+
+/// Discards the value on top of the stack.
+pub fn drop(e: &mut Evaluator, _: &mut Context) -> Result<(), EvalError> {
+    e.pop()?;
+    Ok(())
+}
+
+/// `(vector-map f v)` calls `f` on each element and collects the results in
+/// a new vector.
+pub fn vector_map(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 2)?;
+    let v = vector_elements(args[1], ctx)?;
+    let f = combiner(args[0], ctx)?;
+    let mut q = VecDeque::new();
+    for h in &v {
+        queue_call(f, vec![*h], &mut q, ctx);
+    }
+    q.push_back(EvalItem::Operand(Handle::Nil));
+    for _ in 0..v.len() {
+        q.push_back(EvalItem::Operator(cons, "cons"));
+    }
+    q.push_back(EvalItem::Operand(Handle::Nil));
+    q.push_back(EvalItem::Operator(cons, "cons"));
+    q.push_back(EvalItem::Operator(list_to_vector, "list->vector"));
+    e.push_front(q);
+    Ok(())
+}
+
+/// Runs `f` on the hash table `h` and the heap it lives in.
+fn with_table<T>(
+    h: Handle,
+    ctx: &Context,
+    f: impl FnOnce(&HashTable, &GcHeap) -> T,
+) -> Result<T, EvalError> {
+    match ctx.heap.try_get_ref(h)?.as_ref() {
+        Sexp::HashTable(t) => Ok(f(t, &ctx.heap)),
+        _ => Err(EvalError::TypeError(String::from("expected a hash table"))),
+    }
+}
+
+fn with_table_mut(
+    h: Handle,
+    ctx: &mut Context,
+    f: impl FnOnce(&mut HashTable),
+) -> Result<(), EvalError> {
+    if let Sexp::HashTable(t) = ctx.heap.try_get_mut_ref(h)? {
+        f(t);
+    }
+    Ok(())
+}
+
+/// `(make-hash-table)` compares keys with `equal`; `(make-hash-table 'eq)`
+/// and `(make-hash-table 'eqv)` pick the other comparisons.
+pub fn make_hash_table(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = ctx.heap.try_get_ref(e.pop()?)?.to_handle_list(ctx)?;
+    let kind = match args.as_slice() {
+        [] => Some(TableKind::Equal),
+        [Handle::Symbol(sym)] => ctx
+            .interner
+            .string_from_symbol(*sym)
+            .and_then(TableKind::from_name),
+        [_] => None,
+        _ => return Err(EvalError::InvalidNumberOfArguments),
+    };
+    let kind = kind.ok_or(EvalError::TypeError(String::from(
+        "expected eq, eqv or equal",
+    )))?;
+    e.push(ctx.heap.alloc(Sexp::HashTable(HashTable::new(kind))));
+    Ok(())
+}
+
+/// `(hash-table-ref/default t key default)`
+pub fn hash_table_ref(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 3)?;
+    let val = with_table(args[0], ctx, |t, heap| t.get(args[1], heap))?;
+    e.push(val.unwrap_or(args[2]));
+    Ok(())
+}
+
+pub fn hash_table_set(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 3)?;
+    let (hash, slot) = with_table(args[0], ctx, |t, heap| t.find(args[1], heap))?;
+    with_table_mut(args[0], ctx, |t| t.insert(hash, slot, args[1], args[2]))?;
+    e.push(Handle::Inert);
+    Ok(())
+}
+
+pub fn hash_table_delete(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 2)?;
+    let (_, slot) = with_table(args[0], ctx, |t, heap| t.find(args[1], heap))?;
+    if let Some(slot) = slot {
+        with_table_mut(args[0], ctx, |t| t.remove(slot))?;
+    }
+    e.push(Handle::Inert);
+    Ok(())
+}
+
+pub fn hash_table_contains(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 2)?;
+    let (_, slot) = with_table(args[0], ctx, |t, heap| t.find(args[1], heap))?;
+    e.push(Handle::Boolean(slot.is_some()));
+    Ok(())
+}
+
+pub fn hash_table_keys(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let keys = with_table(args[0], ctx, |t, _| {
+        t.entries().iter().map(|entry| entry.key).collect()
+    })?;
+    e.push(Sexp::from_handle_list(keys, ctx));
+    Ok(())
+}
+
+pub fn hash_table_values(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let values = with_table(args[0], ctx, |t, _| {
+        t.entries().iter().map(|entry| entry.val).collect()
+    })?;
+    e.push(Sexp::from_handle_list(values, ctx));
+    Ok(())
+}
+
+pub fn hash_table_count(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let count = with_table(args[0], ctx, |t, _| t.len())?;
+    e.push(Handle::Integer(count as i64));
+    Ok(())
+}
+
+/// `(hash-table-walk t f)` calls `(f key value)` on every entry. The entries
+/// are taken before the first call, so `f` may modify the table.
+pub fn hash_table_walk(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 2)?;
+    let entries = with_table(args[0], ctx, |t, _| t.entries().to_vec())?;
+    let f = combiner(args[1], ctx)?;
+    let mut q = VecDeque::new();
+    for entry in entries {
+        queue_call(f, vec![entry.key, entry.val], &mut q, ctx);
+        q.push_back(EvalItem::Operator(drop, "drop"));
+    }
+    q.push_back(EvalItem::Operand(Handle::Inert));
+    e.push_front(q);
+    Ok(())
+}
+
+/// Checks the arguments of `set-reader-macro!` and `set-dispatch-macro!`.
+fn macro_args(e: &mut Evaluator, ctx: &Context) -> Result<(char, Handle), EvalError> {
+    let args = args_n(e, ctx, 2)?;
+    let c = match args[0] {
+        Handle::Char(c) => c,
+        _ => return Err(EvalError::TypeError(String::from("expected a character"))),
+    };
+    combiner(args[1], ctx)?;
+    Ok((c, args[1]))
+}
+
+/// `(set-reader-macro! c f)` makes the reader call `(f port)` on a token
+/// starting with `c` and use its result as the datum.
+pub fn set_reader_macro(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let (c, proc) = macro_args(e, ctx)?;
+    ctx.readtable.set_macro(c, proc);
+    e.push(Handle::Inert);
+    Ok(())
+}
+
+/// `(set-dispatch-macro! c f)` does the same for `#c`.
+pub fn set_dispatch_macro(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let (c, proc) = macro_args(e, ctx)?;
+    ctx.readtable.set_dispatch(c, proc);
+    e.push(Handle::Inert);
+    Ok(())
+}
+
+fn port_arg(e: &mut Evaluator, ctx: &Context) -> Result<Port, EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    match ctx.heap.try_get_ref(args[0])?.as_ref() {
+        Sexp::Port(port) => Ok(port.clone()),
+        _ => Err(EvalError::TypeError(String::from("expected a port"))),
+    }
+}
+
+/// `(read-char port)` returns the next character, or `#f` at the end.
+pub fn read_char(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let port = port_arg(e, ctx)?;
+    e.push(
+        port.read_char()
+            .map_or(Handle::Boolean(false), Handle::Char),
     );
-    env.def(
-        ctx.interner.intern("cdr"),
-        ctx.heap.alloc(Sexp::Builtin(cdr)),
+    Ok(())
+}
+
+/// `(peek-char port)` is `read-char` without moving the port.
+pub fn peek_char(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let port = port_arg(e, ctx)?;
+    e.push(
+        port.peek_char()
+            .map_or(Handle::Boolean(false), Handle::Char),
     );
+    Ok(())
+}
+
+/// `(read port)` reads a datum with the reader, reader macros included.
+pub fn read(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    let port = port_arg(e, ctx)?;
+    let mut parser = Parser::for_port(&port);
+    let datum = parser
+        .next_form(ctx, e)
+        .map_err(|err| EvalError::ReadError(err.r#type.to_string()))?;
+    port.set_pos(parser.pos());
+    match datum {
+        Some(h) => e.push(h),
+        None => {
+            return Err(EvalError::ReadError(String::from(
+                "unexpected end of input",
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// `(write datum)` prints `datum` so that it reads back, with labels for
+/// shared and circular structure.
+pub fn write(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    print_arg(e, ctx, PrintMode::Write)
+}
+
+/// `(display datum)` prints `datum` with strings and characters as their
+/// raw text.
+pub fn display(e: &mut Evaluator, ctx: &mut Context) -> Result<(), EvalError> {
+    print_arg(e, ctx, PrintMode::Display)
+}
+
+fn print_arg(e: &mut Evaluator, ctx: &mut Context, mode: PrintMode) -> Result<(), EvalError> {
+    let args = args_n(e, ctx, 1)?;
+    let options = PrintOptions {
+        mode,
+        ..PrintOptions::default()
+    };
+    print!("{}", print(args[0], ctx, options));
+    e.push(Handle::Inert);
+    Ok(())
+}
+
+/// Builtins that receive their operands unevaluated, by the name they are
+/// bound to in the global environment. Heap images refer to builtins by these
+/// names.
+pub const BUILTINS: &[(&str, BuiltinFn)] = &[
+    ("add", add),
+    ("sub", sub),
+    ("mul", mul),
+    ("div", div),
+    ("eval", eval),
+    ("vau", vau),
+    ("def", def),
+    ("wrap", wrap),
+    ("car", car),
+    ("cdr", cdr),
+    ("quote", quote),
+    ("quasiquote", quasiquote),
+];
+
+/// Builtins that are bound wrapped, so their arguments get evaluated.
+pub const APPLICATIVES: &[(&str, BuiltinFn)] = &[
+    ("gensym", gensym),
+    ("make-symbol", make_symbol),
+    ("numerator", numerator),
+    ("denominator", denominator),
+    ("floor", floor),
+    ("round", round),
+    ("exact->inexact", exact_to_inexact),
+    ("make-vector", make_vector),
+    ("vector-ref", vector_ref),
+    ("vector-set!", vector_set),
+    ("vector-length", vector_length),
+    ("vector->list", vector_to_list),
+    ("list->vector", list_to_vector),
+    ("vector-map", vector_map),
+    ("make-hash-table", make_hash_table),
+    ("hash-table-ref/default", hash_table_ref),
+    ("hash-table-set!", hash_table_set),
+    ("hash-table-delete!", hash_table_delete),
+    ("hash-table-contains?", hash_table_contains),
+    ("hash-table-keys", hash_table_keys),
+    ("hash-table-values", hash_table_values),
+    ("hash-table-count", hash_table_count),
+    ("hash-table-walk", hash_table_walk),
+    ("set-reader-macro!", set_reader_macro),
+    ("set-dispatch-macro!", set_dispatch_macro),
+    ("read-char", read_char),
+    ("peek-char", peek_char),
+    ("read", read),
+    ("write", write),
+    ("display", display),
+];
+
+pub fn lookup_builtin(name: &str) -> Option<(BuiltinFn, &'static str)> {
+    BUILTINS
+        .iter()
+        .chain(APPLICATIVES)
+        .find(|(n, _)| *n == name)
+        .map(|(n, f)| (*f, *n))
+}
+
+pub fn global_env(ctx: &mut Context) -> Handle {
+    let mut env = Env::new(None);
+    for (name, func) in BUILTINS {
+        env.def(
+            ctx.interner.intern(name),
+            ctx.heap.alloc(Sexp::Builtin(*func, name)),
+        );
+    }
+    for (name, func) in APPLICATIVES {
+        let builtin = ctx.heap.alloc(Sexp::Builtin(*func, name));
+        env.def(
+            ctx.interner.intern(name),
+            ctx.heap.alloc(Sexp::WrappedProc(builtin)),
+        );
+    }
     ctx.heap.alloc(Sexp::Env(env))
 }
@@ -1,8 +1,9 @@
 use crate::context::Context;
-use crate::context::gc_heap::Handle;
+use crate::context::gc_heap::{Handle, Mark};
 use crate::sexp::{Sexp, Symbol};
 use std::collections::HashMap;
 
+#[derive(Clone)]
 pub struct Env {
     bindings: HashMap<Symbol, Handle>,
     pub outer: Option<Handle>,
@@ -12,20 +13,24 @@ impl Env {
     pub fn new(outer: Option<Handle>) -> Self {
         Self {
             bindings: HashMap::new(),
-            outer: outer,
+            outer,
         }
     }
 
-    pub fn def(self: &mut Self, sym: Symbol, handle: Handle) {
+    pub fn def(&mut self, sym: Symbol, handle: Handle) {
         self.bindings.insert(sym, handle);
     }
 
-    pub fn lookup(self: &Self, sym: Symbol, ctx: &Context) -> Option<Handle> {
+    pub fn bindings(&self) -> impl Iterator<Item = (Symbol, Handle)> + '_ {
+        self.bindings.iter().map(|(sym, handle)| (*sym, *handle))
+    }
+
+    pub fn lookup(&self, sym: Symbol, ctx: &Context) -> Option<Handle> {
         match self.bindings.get(&sym) {
             Some(handle) => Some(*handle),
             None => match self.outer {
                 Some(env_h) => {
-                    if let Sexp::Env(env) = ctx.heap.get_ref(env_h) {
+                    if let Sexp::Env(env) = ctx.heap.get_ref(env_h).as_ref() {
                         env.lookup(sym, ctx)
                     } else {
                         unreachable!()
@@ -36,3 +41,12 @@ impl Env {
         }
     }
 }
+
+impl Mark for Env {
+    fn mark(&self, gray: &mut Vec<Handle>) {
+        gray.extend(self.bindings.values().copied());
+        if let Some(outer) = self.outer {
+            gray.push(outer);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! The `maxlisp fmt` source formatter.
+//!
+//! Works on the lossless syntax tree, so comments survive. A form that fits
+//! in the remaining width is printed on one line. Otherwise its items go on
+//! lines of their own: the arguments of a call line up under the first one,
+//! forms in `BODY_FORMS` keep their leading arguments on the first line and
+//! indent the rest as a body, and other lists line up under their first item.
+//! Blank lines between items are kept, but never more than one.
+
+use crate::cst::{Cst, CstToken, CstTrivia, Node};
+use crate::lexer::{TokenType, TriviaType};
+use crate::parser::ParseError;
+
+/// Forms indented as a body, with the number of arguments that stay on the
+/// first line.
+const BODY_FORMS: &[(&str, usize)] = &[("vau", 2), ("def", 1), ("if", 1)];
+
+/// How much a body is indented past its form's `(`.
+const BODY_INDENT: usize = 2;
+
+pub fn format(source: &str, width: usize) -> Result<String, ParseError> {
+    let cst = Cst::parse(source)?;
+    let mut f = Formatter {
+        out: String::new(),
+        width,
+        tail: 0,
+    };
+    for form in &cst.forms {
+        let blank = f.comments(&form.first().leading, 0);
+        if !f.out.is_empty() {
+            f.newline(0, blank);
+        }
+        f.node(form, false);
+    }
+    f.comments(&cst.trailing, 0);
+    if !f.out.is_empty() {
+        f.newline(0, false);
+    }
+    Ok(f.out)
+}
+
+struct Formatter {
+    out: String,
+    width: usize,
+    /// The width of the `)`s that follow what is being printed.
+    tail: usize,
+}
+
+impl Formatter {
+    fn column(&self) -> usize {
+        let line = match self.out.rfind('\n') {
+            Some(i) => &self.out[i + 1..],
+            None => &self.out,
+        };
+        line.chars().count()
+    }
+
+    /// Starts a new line indented by `indent`, after an empty one if `blank`.
+    fn newline(&mut self, indent: usize, blank: bool) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+        self.out.push('\n');
+        if blank {
+            self.out.push('\n');
+        }
+        self.out.push_str(&" ".repeat(indent));
+    }
+
+    /// Prints the comments in the trivia before a token that goes at
+    /// `indent`. A comment that was on the same line as what came before
+    /// stays on it; the others get lines of their own. Returns whether there
+    /// was a blank line before the token, and leaves the output at the end of
+    /// the last comment, if any.
+    fn comments(&mut self, trivia: &[CstTrivia], indent: usize) -> bool {
+        let mut newlines = 0;
+        for t in trivia {
+            if t.r#type == TriviaType::WHITESPACE {
+                newlines += t.text.matches('\n').count();
+                continue;
+            }
+            if self.out.is_empty() {
+                // The start of the file.
+            } else if newlines == 0 {
+                self.out.push(' ');
+            } else {
+                self.newline(indent, newlines > 1);
+            }
+            self.out.push_str(t.text.trim_end());
+            newlines = 0;
+        }
+        newlines > 1
+    }
+
+    /// Prints `node` starting at the current column, on one line if it fits.
+    /// Lists in `data` are quoted and laid out as data rather than as calls.
+    fn node(&mut self, node: &Node, data: bool) {
+        if let Some(text) = flat(node, false)
+            && self.fits(&text)
+        {
+            self.out.push_str(&text);
+            return;
+        }
+        match node {
+            Node::Atom(t) => self.out.push_str(t.text),
+            Node::Prefixed { prefix, datum } => {
+                let data = match prefix.r#type {
+                    TokenType::QUOTE | TokenType::QUASIQUOTE => true,
+                    TokenType::UNQUOTE | TokenType::UNQUOTE_SPLICING => false,
+                    _ => data,
+                };
+                self.out.push_str(prefix.text);
+                self.item(datum, self.column(), Place::Here, data, self.tail);
+            }
+            Node::List { open, items, close } => self.list(open, items, close, data),
+        }
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        self.column() + text.chars().count() + self.tail <= self.width
+    }
+
+    /// Prints an item of a list or a prefixed datum with the comments before
+    /// it. An item that can't go on the current line goes on a new one at
+    /// `indent`. `tail` is the width of the `)`s right after it.
+    fn item(&mut self, node: &Node, indent: usize, place: Place, data: bool, tail: usize) {
+        let leading = &node.first().leading;
+        let blank = self.comments(leading, indent);
+        let place = match place {
+            _ if has_comment(leading) => Place::NewLine,
+            Place::Fill => match flat(node, true) {
+                Some(text) if self.fits(&format!(" {}", text)) => Place::AfterSpace,
+                _ => Place::NewLine,
+            },
+            place => place,
+        };
+        match place {
+            Place::NewLine => self.newline(indent, blank),
+            Place::AfterSpace => self.out.push(' '),
+            Place::Here | Place::Fill => {}
+        }
+        let outer = self.tail;
+        self.tail = tail;
+        self.node(node, data);
+        self.tail = outer;
+    }
+
+    fn list(&mut self, open: &CstToken, items: &[Node], close: &CstToken, data: bool) {
+        let start = self.column();
+        self.out.push_str(open.text);
+        let head = match items.first() {
+            Some(Node::Atom(t)) if !data && open.r#type == TokenType::LPAREN => match t.r#type {
+                TokenType::SYMBOL => Some(t.text),
+                _ => None,
+            },
+            _ => None,
+        };
+        let body = head.and_then(|head| {
+            BODY_FORMS
+                .iter()
+                .find(|(name, _)| *name == head)
+                .map(|(_, n)| *n)
+        });
+        // Only the last item is followed by this list's `)`.
+        let last = items.len().saturating_sub(1);
+        let closed = self.tail + close.text.len();
+        let tail = |i| if i == last { closed } else { 0 };
+        let mut indent = start + open.text.len();
+        let mut place = Place::NewLine;
+        let mut rest = items.iter().enumerate();
+        if let Some((i, first)) = rest.next() {
+            self.item(first, indent, Place::Here, data, tail(i));
+        }
+        match (head, body) {
+            (Some(_), Some(n)) => {
+                for (i, arg) in rest.by_ref().take(n) {
+                    self.item(arg, indent, Place::AfterSpace, data, tail(i));
+                }
+                indent = start + BODY_INDENT;
+            }
+            (Some(_), None) => {
+                indent = self.column() + 1;
+                if let Some((i, arg)) = rest.next() {
+                    self.item(arg, indent, Place::AfterSpace, data, tail(i));
+                }
+            }
+            _ if data || open.r#type == TokenType::VECTOR_START => place = Place::Fill,
+            _ => {}
+        }
+        for (i, item) in rest {
+            self.item(item, indent, place, data, tail(i));
+        }
+        let blank = self.comments(&close.leading, indent);
+        if has_comment(&close.leading) {
+            self.newline(indent, blank);
+        }
+        self.out.push_str(close.text);
+    }
+}
+
+/// Where an item goes relative to what was printed before it.
+#[derive(Clone, Copy)]
+enum Place {
+    /// Right after it.
+    Here,
+    AfterSpace,
+    NewLine,
+    /// After a space if it fits on the line, otherwise on a new line.
+    Fill,
+}
+
+fn has_comment(trivia: &[CstTrivia]) -> bool {
+    trivia.iter().any(|t| t.r#type != TriviaType::WHITESPACE)
+}
+
+/// `node` on one line, or `None` if it has to span several because of a
+/// comment or a multi-line literal. The trivia in front of the node itself
+/// only counts when `leading`.
+fn flat(node: &Node, leading: bool) -> Option<String> {
+    if leading && has_comment(&node.first().leading) {
+        return None;
+    }
+    match node {
+        Node::Atom(t) if t.text.contains('\n') => None,
+        Node::Atom(t) => Some(t.text.to_string()),
+        Node::Prefixed { prefix, datum } => Some(format!("{}{}", prefix.text, flat(datum, true)?)),
+        Node::List { open, items, close } => {
+            if has_comment(&close.leading) {
+                return None;
+            }
+            let items = items
+                .iter()
+                .map(|item| flat(item, true))
+                .collect::<Option<Vec<String>>>()?;
+            Some(format!("{}{}{}", open.text, items.join(" "), close.text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCES: &[&str] = &[
+        "(def fac (vau (x) % (if (eq x 0) 1 (mul x (fac (sub x 1)))))) ; trailing\n",
+        "#!/usr/bin/env maxlisp\n; a comment\n\n\n\n(a b)   (c\n d)\n",
+        "(def data '(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25))",
+        "(long-function-name argument-number-one argument-number-two #(1 2 3) #| c |# x)",
+        "(f ; after f\n   x\n   ; before the )\n)\n`(a ,b ,@(c d)) #;(ignored form) #0=(e . #0#)",
+        "(g \"a string\nover two lines\" #hash((a . 1) (b . 2)) #\\space)",
+    ];
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for width in [20, 40, 80] {
+            for source in SOURCES {
+                let once = format(source, width).unwrap();
+                let twice = format(&once, width).unwrap();
+                assert_eq!(once, twice, "width {}:\n{}", width, source);
+            }
+        }
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        for source in SOURCES {
+            let formatted = format(source, 40).unwrap();
+            for comment in [
+                "; trailing",
+                "; a comment",
+                "#| c |#",
+                "; after f",
+                "; before the )",
+            ] {
+                assert_eq!(
+                    source.contains(comment),
+                    formatted.contains(comment),
+                    "{}",
+                    comment
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn short_forms_stay_on_one_line() {
+        assert_eq!(format("(a   b\n  c)", 80).unwrap(), "(a b c)\n");
+        assert_eq!(format("(a)\n\n\n\n(b)", 80).unwrap(), "(a)\n\n(b)\n");
+    }
+
+    #[test]
+    fn body_forms_indent_their_body() {
+        let formatted = format("(def fac (vau (x) % (if (eq x 0) 1 (mul x 2))))", 34).unwrap();
+        assert_eq!(
+            formatted,
+            "(def fac\n  (vau (x) %\n    (if (eq x 0) 1 (mul x 2))))\n"
+        );
+    }
+
+    #[test]
+    fn calls_align_their_arguments() {
+        let formatted = format("(function argument-one argument-two)", 30).unwrap();
+        assert_eq!(
+            formatted,
+            "(function argument-one\n          argument-two)\n"
+        );
+    }
+
+    #[test]
+    fn unbalanced_input_is_an_error() {
+        assert!(format("(a (b)", 80).is_err());
+        assert!(format("a)", 80).is_err());
+    }
+}
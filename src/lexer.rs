@@ -1,14 +1,50 @@
+use crate::context::readtable::Readtable;
 use crate::parser::{ParseError, ParseErrorType};
+use crate::sexp::CHAR_NAMES;
 
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 pub enum TokenType {
     LPAREN,
+    VECTOR_START,
+    HASH_TABLE_START,
     RPAREN,
     DOT,
     INTEGER,
     REAL,
+    RATIONAL,
     STRING,
     SYMBOL,
+    BOOLEAN,
+    CHARACTER,
+    INERT,
+    IGNORE,
+    DATUM_COMMENT,
+    READER_MACRO,
+    DISPATCH_MACRO,
+    QUOTE,
+    QUASIQUOTE,
+    UNQUOTE,
+    UNQUOTE_SPLICING,
+    LABEL_DEF,
+    LABEL_REF,
+}
+
+/// The text between tokens, which the parser skips.
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriviaType {
+    WHITESPACE,
+    LINE_COMMENT,
+    BLOCK_COMMENT,
+    SHEBANG,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Trivia {
+    pub r#type: TriviaType,
+    pub pos: usize,
+    pub end: usize,
 }
 
 #[derive(Debug, PartialEq)]
@@ -18,129 +54,501 @@ pub struct Token {
     pub pos: usize,
 }
 pub struct Lexer<'a> {
-    source: &'a String,
+    source: &'a str,
     start: usize,
     pos: usize,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source: &'a String) -> Self {
+    pub fn new(source: &'a str) -> Self {
         Self {
-            source: source,
+            source,
             start: 0,
             pos: 0,
         }
     }
 
-    fn is_eof(self: &Self) -> bool {
+    /// The byte offset where lexing continues.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn set_pos(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn is_eof(&self) -> bool {
         self.pos >= self.source.len()
     }
 
-    fn peek(self: &Self) -> Option<u8> {
+    fn peek(&self) -> Option<char> {
         if self.is_eof() {
             None
         } else {
-            Some(self.source.as_bytes()[self.pos])
+            self.source[self.pos..].chars().next()
+        }
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.source[self.pos..].chars().nth(offset)
+    }
+
+    fn advance(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
         }
     }
 
-    fn advance(self: &mut Self) {
-        if self.pos < self.source.len() {
-            self.pos += 1;
+    /// Skips to the end of the line, leaving the line break.
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.advance();
         }
     }
 
-    fn skip_space(self: &mut Self) {
+    /// Skips a `#| ... |#` comment, which may contain nested block comments.
+    fn skip_block_comment(&mut self) -> Result<(), ParseError> {
+        let start = self.pos;
+        let mut depth = 0;
         loop {
-            match self.peek() {
-                Some(c) => {
-                    if !c.is_ascii_whitespace() {
-                        break;
-                    }
+            match (self.peek(), self.peek_at(1)) {
+                (Some('#'), Some('|')) => {
+                    depth += 1;
+                    self.advance();
+                }
+                (Some('|'), Some('#')) => {
+                    depth -= 1;
+                    self.advance();
+                }
+                (Some(_), _) => {}
+                (None, _) => {
+                    return Err(self.error(ParseErrorType::CommentNotTerminated, start));
                 }
-                None => break,
             }
             self.advance();
+            if depth == 0 {
+                return Ok(());
+            }
         }
     }
 
-    fn make_token(self: &Self, r#type: TokenType) -> Token {
+    /// Lexes the whitespace or comment at the current position, if there is
+    /// one: a run of whitespace, a `;` line comment, a `#| |#` block comment
+    /// or a `#!` line at the very start of the source.
+    pub fn next_trivia(&mut self) -> Result<Option<Trivia>, ParseError> {
+        let pos = self.pos;
+        let r#type = match (self.peek(), self.peek_at(1)) {
+            (Some('#'), Some('!')) if pos == 0 => {
+                self.skip_line();
+                TriviaType::SHEBANG
+            }
+            (Some(';'), _) => {
+                self.skip_line();
+                TriviaType::LINE_COMMENT
+            }
+            (Some('#'), Some('|')) => {
+                self.skip_block_comment()?;
+                TriviaType::BLOCK_COMMENT
+            }
+            (Some(c), _) if c.is_whitespace() => {
+                while self.peek().is_some_and(char::is_whitespace) {
+                    self.advance();
+                }
+                TriviaType::WHITESPACE
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(Trivia {
+            r#type,
+            pos,
+            end: self.pos,
+        }))
+    }
+
+    fn skip_space(&mut self) -> Result<(), ParseError> {
+        while self.next_trivia()?.is_some() {}
+        Ok(())
+    }
+
+    fn make_token(&self, r#type: TokenType) -> Token {
         Token {
-            r#type: r#type,
+            r#type,
             val: String::from(&self.source[self.start..self.pos]),
             pos: self.start,
         }
     }
 
-    fn integer(self: &mut Self) -> Result<Option<Token>, ParseError> {
-        loop {
-            match self.peek() {
-                Some(c) => {
-                    if !c.is_ascii_digit() {
-                        break;
-                    }
-                }
-                None => break,
-            };
+    /// True if `c` ends a token. Characters with reader macros in `readtable`
+    /// do, like `(` does.
+    fn is_delimiter(&self, c: char, readtable: &Readtable) -> bool {
+        c == '('
+            || c == ')'
+            || c == ';'
+            || c == '"'
+            || c.is_whitespace()
+            || readtable.get_macro(c).is_some()
+    }
+
+    fn skip_to_delimiter(&mut self, readtable: &Readtable) {
+        while let Some(c) = self.peek() {
+            if self.is_delimiter(c, readtable) {
+                break;
+            }
             self.advance();
         }
-        Ok(Some(self.make_token(TokenType::INTEGER)))
     }
 
-    fn string(self: &mut Self) -> Result<Option<Token>, ParseError> {
-        self.advance(); // skip the '"'
+    /// True at `#hash(`, `#hasheqv(` or `#hasheq(`.
+    fn at_hash_table(&self) -> bool {
+        let rest = &self.source[self.pos..];
+        ["#hash(", "#hasheqv(", "#hasheq("]
+            .iter()
+            .any(|prefix| rest.starts_with(prefix))
+    }
+
+    /// True at a `#n=` or `#n#` datum label.
+    fn at_label(&self) -> bool {
+        let digits = self.source[self.pos + 1..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        digits > 0 && matches!(self.peek_at(digits + 1), Some('=' | '#'))
+    }
+
+    fn label(&mut self) -> Result<Option<Token>, ParseError> {
+        self.advance(); // skip the '#'
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+        }
+        let r#type = match self.peek() {
+            Some('=') => TokenType::LABEL_DEF,
+            _ => TokenType::LABEL_REF,
+        };
+        self.advance();
+        Ok(Some(self.make_token(r#type)))
+    }
+
+    /// True if the text at the current position can only be a number.
+    fn at_number(&self, readtable: &Readtable) -> bool {
+        let digit_at = |offset| {
+            self.peek_at(offset)
+                .is_some_and(|c: char| c.is_ascii_digit())
+        };
+        match self.peek() {
+            Some('0'..='9') => true,
+            Some('.') => digit_at(1),
+            Some('+' | '-') => digit_at(1) || (self.peek_at(1) == Some('.') && digit_at(2)),
+            Some('#') => {
+                let word = self.source[self.pos..]
+                    .split(|c| self.is_delimiter(c, readtable))
+                    .next()
+                    .unwrap_or("");
+                !matches!(word, "#inert" | "#ignore")
+                    && matches!(
+                        self.peek_at(1).map(|c| c.to_ascii_lowercase()),
+                        Some('x' | 'b' | 'o' | 'd' | 'e' | 'i')
+                    )
+            }
+            _ => false,
+        }
+    }
+
+    /// Lexes a numeric literal up to the next delimiter. It is a rational if
+    /// it has a `/`, and a real if it is in radix 10 and has a fraction or an
+    /// exponent; the parser checks the digits.
+    fn number(&mut self, readtable: &Readtable) -> Result<Option<Token>, ParseError> {
+        self.skip_to_delimiter(readtable);
+        let mut text = &self.source[self.start..self.pos];
+        let mut radix10 = true;
+        while let Some(prefix) = text.strip_prefix('#') {
+            let mut chars = prefix.chars();
+            match chars.next().map(|c| c.to_ascii_lowercase()) {
+                Some('x' | 'b' | 'o') => radix10 = false,
+                Some('d' | 'e' | 'i') => {}
+                _ => return Err(self.error(ParseErrorType::MalformedNumber, self.start)),
+            }
+            text = chars.as_str();
+        }
+        if text.contains('/') {
+            Ok(Some(self.make_token(TokenType::RATIONAL)))
+        } else if radix10 && text.contains(['.', 'e', 'E']) {
+            Ok(Some(self.make_token(TokenType::REAL)))
+        } else {
+            Ok(Some(self.make_token(TokenType::INTEGER)))
+        }
+    }
+
+    /// An error spanning from `pos` to where lexing stopped.
+    fn error(&self, r#type: ParseErrorType, pos: usize) -> ParseError {
+        ParseError::new(r#type, pos, self.pos)
+    }
+
+    /// Reads hex digits up to `terminator` and returns the character they
+    /// encode. Used by the `\x41;` and `\u{41}` escapes.
+    fn hex_escape(&mut self, terminator: char, escape_pos: usize) -> Result<char, ParseError> {
+        let mut digits = String::new();
         loop {
             match self.peek() {
-                Some(b'"') => break,
-                Some(_) => {}
-                None => {
-                    return Err(ParseError {
-                        r#type: ParseErrorType::StringNotTerminated,
-                        pos: self.start,
-                    });
-                }
+                Some(c) if c == terminator => break,
+                Some(c) if c.is_ascii_hexdigit() => digits.push(c),
+                _ => return Err(self.error(ParseErrorType::InvalidEscape, escape_pos)),
             }
             self.advance();
         }
-        self.advance(); // skip the '"'
-        Ok(Some(self.make_token(TokenType::STRING)))
+        self.advance(); // skip the terminator
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(self.error(ParseErrorType::InvalidEscape, escape_pos))
+    }
+
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let escape_pos = self.pos;
+        self.advance(); // skip the '\\'
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error(ParseErrorType::StringNotTerminated, self.start)),
+        };
+        self.advance();
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'a' => Ok('\u{7}'),
+            'b' => Ok('\u{8}'),
+            '0' => Ok('\0'),
+            '\\' | '"' | '|' => Ok(c),
+            'x' => self.hex_escape(';', escape_pos),
+            'u' if self.peek() == Some('{') => {
+                self.advance();
+                self.hex_escape('}', escape_pos)
+            }
+            _ => Err(self.error(ParseErrorType::InvalidEscape, escape_pos)),
+        }
     }
 
-    fn symbol(self: &mut Self) -> Result<Option<Token>, ParseError> {
+    /// The token's value is the string's contents, without the quotes and
+    /// with escapes decoded.
+    fn string(&mut self) -> Result<Option<Token>, ParseError> {
+        self.advance(); // skip the '"'
+        let mut val = String::new();
         loop {
             match self.peek() {
-                Some(c) => {
-                    if c == b'(' || c == b')' || c.is_ascii_whitespace() {
-                        break;
+                Some('"') => break,
+                Some('\\') => match self.escape() {
+                    Ok(c) => val.push(c),
+                    Err(e) => {
+                        self.skip_string();
+                        return Err(e);
                     }
+                },
+                Some(c) => {
+                    val.push(c);
+                    self.advance();
                 }
-                None => break,
+                None => return Err(self.error(ParseErrorType::StringNotTerminated, self.start)),
             }
+        }
+        self.advance(); // skip the '"'
+        Ok(Some(Token {
+            r#type: TokenType::STRING,
+            val,
+            pos: self.start,
+        }))
+    }
+
+    /// Skips the rest of a string after a bad escape, so lexing can carry on
+    /// after it.
+    fn skip_string(&mut self) {
+        while let Some(c) = self.peek() {
             self.advance();
+            match c {
+                '"' => break,
+                '\\' => self.advance(),
+                _ => {}
+            }
         }
-        Ok(Some(self.make_token(TokenType::SYMBOL)))
     }
 
-    pub fn next_token(self: &mut Self) -> Result<Option<Token>, ParseError> {
-        self.skip_space();
+    /// Lexes `#\a`, `#\space` or `#\x3bb`. The character right after the
+    /// backslash is taken even if it is a delimiter, so `#\(` and `#\ ` work.
+    /// The token's value is the character itself.
+    fn character(&mut self, readtable: &Readtable) -> Result<Option<Token>, ParseError> {
+        self.advance(); // skip the '#'
+        self.advance(); // skip the '\\'
+        let name_start = self.pos;
+        if self.peek().is_none() {
+            return Err(self.error(ParseErrorType::InvalidCharacter, self.start));
+        }
+        self.advance();
+        self.skip_to_delimiter(readtable);
+        let name = &self.source[name_start..self.pos];
+        let mut chars = name.chars();
+        let first = chars.next().unwrap();
+        let c = if chars.as_str().is_empty() {
+            Some(first)
+        } else if let Some((_, c)) = CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+            Some(*c)
+        } else if first == 'x' || first == 'X' {
+            u32::from_str_radix(chars.as_str(), 16)
+                .ok()
+                .and_then(char::from_u32)
+        } else {
+            None
+        };
+        match c {
+            Some(c) => Ok(Some(Token {
+                r#type: TokenType::CHARACTER,
+                val: c.to_string(),
+                pos: self.start,
+            })),
+            None => Err(self.error(ParseErrorType::InvalidCharacter, self.start)),
+        }
+    }
+
+    fn symbol(&mut self, readtable: &Readtable) -> Result<Option<Token>, ParseError> {
+        self.skip_to_delimiter(readtable);
+        match &self.source[self.start..self.pos] {
+            "+inf.0" | "-inf.0" | "+nan.0" | "-nan.0" => Ok(Some(self.make_token(TokenType::REAL))),
+            "#t" | "#true" | "#f" | "#false" => Ok(Some(self.make_token(TokenType::BOOLEAN))),
+            "#inert" => Ok(Some(self.make_token(TokenType::INERT))),
+            "#ignore" => Ok(Some(self.make_token(TokenType::IGNORE))),
+            _ => Ok(Some(self.make_token(TokenType::SYMBOL))),
+        }
+    }
+
+    /// Lexes the next token. Reader macros in `readtable` take precedence
+    /// over the built-in syntax; their tokens only hold the macro characters.
+    pub fn next_token(&mut self, readtable: &Readtable) -> Result<Option<Token>, ParseError> {
+        self.skip_space()?;
         self.start = self.pos;
         match self.peek() {
             None => Ok(None),
-            Some(b'(') => {
+            Some(c) if readtable.get_macro(c).is_some() => {
+                self.advance();
+                Ok(Some(self.make_token(TokenType::READER_MACRO)))
+            }
+            Some('#')
+                if self
+                    .peek_at(1)
+                    .is_some_and(|c| readtable.get_dispatch(c).is_some()) =>
+            {
+                self.advance();
+                self.advance();
+                Ok(Some(self.make_token(TokenType::DISPATCH_MACRO)))
+            }
+            Some('(') => {
                 self.advance();
                 Ok(Some(self.make_token(TokenType::LPAREN)))
             }
-            Some(b')') => {
+            Some(')') => {
                 self.advance();
                 Ok(Some(self.make_token(TokenType::RPAREN)))
             }
-            Some(b'.') => {
+            Some('#') if self.peek_at(1) == Some('(') => {
+                self.advance();
+                self.advance();
+                Ok(Some(self.make_token(TokenType::VECTOR_START)))
+            }
+            Some('#') if self.at_hash_table() => {
+                while self.peek() != Some('(') {
+                    self.advance();
+                }
+                self.advance();
+                Ok(Some(self.make_token(TokenType::HASH_TABLE_START)))
+            }
+            Some('#') if self.peek_at(1) == Some('\\') => self.character(readtable),
+            Some('#') if self.at_label() => self.label(),
+            _ if self.at_number(readtable) => self.number(readtable),
+            Some('.') => {
                 self.advance();
                 Ok(Some(self.make_token(TokenType::DOT)))
             }
-            Some(b'0'..=b'9') => self.integer(),
-            Some(b'"') => self.string(),
-            _ => self.symbol(),
+            Some('"') => self.string(),
+            Some('#') if self.peek_at(1) == Some(';') => {
+                self.advance();
+                self.advance();
+                Ok(Some(self.make_token(TokenType::DATUM_COMMENT)))
+            }
+            Some('\'') => {
+                self.advance();
+                Ok(Some(self.make_token(TokenType::QUOTE)))
+            }
+            Some('`') => {
+                self.advance();
+                Ok(Some(self.make_token(TokenType::QUASIQUOTE)))
+            }
+            Some(',') if self.peek_at(1) == Some('@') => {
+                self.advance();
+                self.advance();
+                Ok(Some(self.make_token(TokenType::UNQUOTE_SPLICING)))
+            }
+            Some(',') => {
+                self.advance();
+                Ok(Some(self.make_token(TokenType::UNQUOTE)))
+            }
+            _ => self.symbol(readtable),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Result<Vec<TokenType>, ParseErrorType> {
+        lex_with(source, &Readtable::default())
+    }
+
+    fn lex_with(source: &str, readtable: &Readtable) -> Result<Vec<TokenType>, ParseErrorType> {
+        let mut lexer = Lexer::new(source);
+        let mut types = vec![];
+        loop {
+            match lexer.next_token(readtable) {
+                Ok(Some(t)) => types.push(t.r#type),
+                Ok(None) => return Ok(types),
+                Err(e) => return Err(e.r#type),
+            }
+        }
+    }
+
+    #[test]
+    fn numeric_prefixes() {
+        let types = lex("#xff #e1.5 #x1e3 #i1/2").unwrap();
+        assert_eq!(
+            types,
+            [
+                TokenType::INTEGER,
+                TokenType::REAL,
+                TokenType::INTEGER,
+                TokenType::RATIONAL
+            ]
+        );
+    }
+
+    #[test]
+    fn non_ascii_after_prefix() {
+        assert!(matches!(lex("#e#λ"), Err(ParseErrorType::MalformedNumber)));
+        assert!(matches!(lex("#e#"), Err(ParseErrorType::MalformedNumber)));
+    }
+
+    #[test]
+    fn reader_macros_end_tokens() {
+        let mut readtable = Readtable::default();
+        readtable.set_macro('!', crate::context::gc_heap::Handle::Nil);
+        let types = lex_with("ab!c 12!", &readtable).unwrap();
+        assert_eq!(
+            types,
+            [
+                TokenType::SYMBOL,
+                TokenType::READER_MACRO,
+                TokenType::SYMBOL,
+                TokenType::INTEGER,
+                TokenType::READER_MACRO
+            ]
+        );
+    }
+}
@@ -1,55 +1,266 @@
 use std::env;
 use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::rc::Rc;
 
+mod cst;
 mod lexer;
 mod parser;
+use cst::Cst;
 use evaluator::EvalItem;
 use evaluator::Evaluator;
 use evaluator::builtins;
 use parser::Parser;
+use sexp::printer::{PrintOptions, print};
 mod context;
 mod evaluator;
+mod formatter;
+mod number;
 mod sexp;
-use context::Context;
+use context::image::ImageError;
+use context::spans::SourceFile;
+use context::{Context, ContextOptions};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("usage: {} file.lsp", args[0]);
-        return;
+fn usage(program: &str) {
+    eprintln!(
+        "usage: {} [--max-heap cells] [--heap-stats] [--check-syntax] [--dump-cst] [--image file] [--dump-image file] file.lsp...",
+        program
+    );
+    eprintln!(
+        "       {} fmt [--check] [--width columns] file.lsp...",
+        program
+    );
+}
+
+/// `maxlisp fmt`: rewrites the files in the canonical style, or with
+/// `--check` only reports the ones that aren't in it. Exits with status 1 if
+/// any file isn't formatted or can't be parsed.
+fn fmt(program: &str, args: &[String]) {
+    let mut check = false;
+    let mut width = 80;
+    let mut files = vec![];
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--width" => match it.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => width = n,
+                _ => {
+                    eprintln!("--width expects a number of columns");
+                    std::process::exit(2);
+                }
+            },
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage(program);
+        std::process::exit(2);
+    }
+
+    let mut failed = false;
+    for file_path in files {
+        let source = match fs::read_to_string(file_path) {
+            Ok(s) => SourceFile::new(file_path, s),
+            Err(e) => {
+                println!("failed to open {}: {}", file_path, e);
+                failed = true;
+                continue;
+            }
+        };
+        let formatted = match formatter::format(source.text(), width) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("{}", e.to_string(&source));
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source.text() {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", file_path);
+            failed = true;
+        } else if let Err(e) = fs::write(file_path, formatted) {
+            println!("failed to write {}: {}", file_path, e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
     }
-    let file_path = &args[1];
+}
+
+fn run_file(file_path: &String, ctx: &mut Context, evaluator: &mut Evaluator) {
     let source = match fs::read_to_string(file_path) {
-        Ok(s) => s,
+        Ok(s) => Rc::new(SourceFile::new(file_path, s)),
         Err(e) => {
             println!("failed to open {}: {}", file_path, e);
             return;
         }
     };
-
-    let mut ctx = Context::new();
     let mut parser = Parser::new(&source);
-    let mut evaluator = Evaluator::new(&mut ctx);
 
     loop {
-        match parser.next_form(&mut ctx) {
+        match parser.next_form(ctx, evaluator) {
             Ok(o) => match o {
                 Some(s) => {
-                    println!("{}", ctx.heap.get_ref(s).to_string(&ctx));
-                    evaluator.push_back(EvalItem::Operand(s));
+                    let s = ctx.root(s);
+                    println!("{}", print(s.handle(), ctx, PrintOptions::default()));
+                    evaluator.push_back(EvalItem::Operand(s.handle()));
                     evaluator.push_back(EvalItem::Operator(builtins::eval, "eval"));
-                    match evaluator.run(&mut ctx) {
+                    match evaluator.run(ctx) {
                         Ok(()) => (),
-                        Err(e) => println!("{:?}", e),
+                        Err(e) => match ctx.spans.get(s.handle()) {
+                            Some(span) => println!("{}: {}", span, e),
+                            None => println!("{}", e),
+                        },
                     };
                 }
                 None => break,
             },
             Err(e) => {
-                println!("{}", e.to_string(file_path, &source));
+                println!("{}", e.to_string(&source));
                 break;
             }
         }
-        println!("");
+        println!();
+    }
+}
+
+/// Prints every syntax error in the file without evaluating it, and returns
+/// whether there were any.
+fn check_file(file_path: &String, ctx: &mut Context, evaluator: &mut Evaluator) -> bool {
+    let source = match fs::read_to_string(file_path) {
+        Ok(s) => Rc::new(SourceFile::new(file_path, s)),
+        Err(e) => {
+            println!("failed to open {}: {}", file_path, e);
+            return true;
+        }
+    };
+    let mut parser = Parser::new(&source);
+    let errors = parser.check(ctx, evaluator);
+    for e in &errors {
+        println!("{}", e.to_string(&source));
+    }
+    !errors.is_empty()
+}
+
+/// Prints the file's concrete syntax tree.
+fn dump_cst(file_path: &String) {
+    let source = match fs::read_to_string(file_path) {
+        Ok(s) => SourceFile::new(file_path, s),
+        Err(e) => {
+            println!("failed to open {}: {}", file_path, e);
+            return;
+        }
+    };
+    match Cst::parse(source.text()) {
+        Ok(cst) => {
+            debug_assert_eq!(cst.to_string(), source.text());
+            print!("{}", cst.dump());
+        }
+        Err(e) => println!("{}", e.to_string(&source)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|arg| arg == "fmt") {
+        fmt(&args[0], &args[2..]);
+        return;
+    }
+    let mut options = ContextOptions::default();
+    let mut heap_stats = false;
+    let mut check_syntax = false;
+    let mut cst = false;
+    let mut image = None;
+    let mut dump_image = None;
+    let mut files = vec![];
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--max-heap" => match it.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => options.max_cells = Some(n),
+                _ => {
+                    eprintln!("--max-heap expects a number of cells");
+                    return;
+                }
+            },
+            "--heap-stats" => heap_stats = true,
+            "--check-syntax" => check_syntax = true,
+            "--dump-cst" => cst = true,
+            "--image" | "--dump-image" => match it.next() {
+                Some(path) if arg == "--image" => image = Some(path),
+                Some(path) => dump_image = Some(path),
+                None => {
+                    eprintln!("{} expects a file name", arg);
+                    return;
+                }
+            },
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() && image.is_none() {
+        usage(&args[0]);
+        return;
+    }
+
+    let mut ctx = match image {
+        Some(path) => {
+            let loaded = fs::File::open(path)
+                .map_err(ImageError::from)
+                .and_then(|f| Context::load_image(BufReader::new(f), options));
+            match loaded {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    println!("failed to load image {}: {}", path, e);
+                    return;
+                }
+            }
+        }
+        None => Context::new(options),
+    };
+    let mut evaluator = Evaluator::new(&mut ctx);
+
+    if cst {
+        for file_path in files {
+            dump_cst(file_path);
+        }
+        return;
+    }
+
+    if check_syntax {
+        let mut failed = false;
+        for file_path in files {
+            failed |= check_file(file_path, &mut ctx, &mut evaluator);
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    for file_path in files {
+        run_file(file_path, &mut ctx, &mut evaluator);
+    }
+
+    if let Some(path) = dump_image {
+        evaluator.reset(&mut ctx);
+        let saved = fs::File::create(path)
+            .map_err(ImageError::from)
+            .and_then(|f| ctx.save_image(BufWriter::new(f)));
+        if let Err(e) = saved {
+            println!("failed to write image {}: {}", path, e);
+        }
+    }
+
+    if heap_stats {
+        eprintln!(
+            "heap: {} cells allocated, {} live",
+            ctx.heap.allocations(),
+            ctx.heap.live_cells()
+        );
     }
 }
@@ -0,0 +1,379 @@
+use crate::context::Context;
+use crate::context::gc_heap::Handle;
+use crate::evaluator::EvalError;
+use crate::parser::ParseErrorType;
+use crate::sexp::Sexp;
+pub mod bigint;
+pub mod rational;
+use bigint::BigInt;
+use rational::Rational;
+
+/// A number taken out of the heap to do arithmetic on. Integers that overflow
+/// an `i64` become bignums, and bignums that fit again become integers.
+/// Dividing exact numbers gives an exact rational, which becomes an integer
+/// again when its denominator is 1. Operations involving a real give a real.
+#[derive(Debug, Clone)]
+pub enum Number {
+    Integer(i64),
+    Big(BigInt),
+    Rational(Rational),
+    Real(f64),
+}
+
+impl Number {
+    pub fn from_handle(h: Handle, ctx: &Context) -> Result<Number, EvalError> {
+        match ctx.heap.try_get_ref(h)?.as_ref() {
+            Sexp::Integer(i) => Ok(Number::Integer(*i)),
+            Sexp::BigInt(b) => Ok(Number::Big(b.clone())),
+            Sexp::Rational(q) => Ok(Number::Rational(q.clone())),
+            Sexp::Real(r) => Ok(Number::Real(*r)),
+            _ => Err(EvalError::TypeError(String::from("expected a number"))),
+        }
+    }
+
+    pub fn into_handle(self, ctx: &mut Context) -> Handle {
+        match self {
+            Number::Integer(i) => Handle::Integer(i),
+            Number::Big(b) => ctx.heap.alloc(Sexp::BigInt(b)),
+            Number::Rational(q) => ctx.heap.alloc(Sexp::Rational(q)),
+            Number::Real(r) => ctx.heap.alloc(Sexp::Real(r)),
+        }
+    }
+
+    /// Demotes `b` to an integer if it fits.
+    pub fn from_big(b: BigInt) -> Number {
+        match b.to_i64() {
+            Some(i) => Number::Integer(i),
+            None => Number::Big(b),
+        }
+    }
+
+    /// Demotes `q` to an integer if its denominator is 1.
+    pub fn from_rational(q: Rational) -> Number {
+        if q.is_integer() {
+            Number::from_big(q.numerator().clone())
+        } else {
+            Number::Rational(q)
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Number::Integer(i) => BigInt::from_i64(*i),
+            Number::Big(b) => b.clone(),
+            Number::Rational(_) | Number::Real(_) => unreachable!(),
+        }
+    }
+
+    fn to_rational(&self) -> Rational {
+        match self {
+            Number::Rational(q) => q.clone(),
+            Number::Real(_) => unreachable!(),
+            n => Rational::from_integer(n.to_big()),
+        }
+    }
+
+    pub fn to_real(&self) -> f64 {
+        match self {
+            Number::Integer(i) => *i as f64,
+            Number::Big(b) => b.to_f64(),
+            Number::Rational(q) => q.to_f64(),
+            Number::Real(r) => *r,
+        }
+    }
+
+    /// The exact number equal to `self`, or `None` for infinities and NaN.
+    pub fn to_exact(&self) -> Option<Number> {
+        match self {
+            Number::Real(r) => Rational::from_f64(*r).map(Number::from_rational),
+            n => Some(n.clone()),
+        }
+    }
+
+    fn arith(
+        &self,
+        other: &Self,
+        int_op: fn(i64, i64) -> Option<i64>,
+        big_op: fn(&BigInt, &BigInt) -> BigInt,
+        rational_op: fn(&Rational, &Rational) -> Rational,
+        real_op: fn(f64, f64) -> f64,
+    ) -> Number {
+        match (self, other) {
+            (Number::Real(_), _) | (_, Number::Real(_)) => {
+                Number::Real(real_op(self.to_real(), other.to_real()))
+            }
+            (Number::Rational(_), _) | (_, Number::Rational(_)) => {
+                Number::from_rational(rational_op(&self.to_rational(), &other.to_rational()))
+            }
+            (Number::Integer(a), Number::Integer(b)) if let Some(i) = int_op(*a, *b) => {
+                Number::Integer(i)
+            }
+            (a, b) => Number::from_big(big_op(&a.to_big(), &b.to_big())),
+        }
+    }
+
+    pub fn add(&self, other: &Self) -> Number {
+        self.arith(
+            other,
+            i64::checked_add,
+            BigInt::add,
+            Rational::add,
+            |a, b| a + b,
+        )
+    }
+
+    pub fn sub(&self, other: &Self) -> Number {
+        self.arith(
+            other,
+            i64::checked_sub,
+            BigInt::sub,
+            Rational::sub,
+            |a, b| a - b,
+        )
+    }
+
+    pub fn mul(&self, other: &Self) -> Number {
+        self.arith(
+            other,
+            i64::checked_mul,
+            BigInt::mul,
+            Rational::mul,
+            |a, b| a * b,
+        )
+    }
+
+    /// Dividing exact numbers gives an exact result, a rational when the
+    /// quotient is not a whole number.
+    pub fn div(&self, other: &Self) -> Result<Number, EvalError> {
+        match (self, other) {
+            (Number::Real(_), _) | (_, Number::Real(_)) => {
+                Ok(Number::Real(self.to_real() / other.to_real()))
+            }
+            (a, b) => a
+                .to_rational()
+                .div(&b.to_rational())
+                .map(Number::from_rational)
+                .ok_or(EvalError::DivisionByZero),
+        }
+    }
+
+    /// An exact value's numerator and denominator in lowest terms. Reals are
+    /// converted to exact first and the parts converted back.
+    pub fn numerator(&self) -> Result<Number, EvalError> {
+        self.map_exact(|q| Number::from_big(q.numerator().clone()))
+    }
+
+    pub fn denominator(&self) -> Result<Number, EvalError> {
+        self.map_exact(|q| Number::from_big(q.denominator().clone()))
+    }
+
+    /// Rounds toward negative infinity. Reals stay real.
+    pub fn floor(&self) -> Number {
+        match self {
+            Number::Real(r) => Number::Real(r.floor()),
+            Number::Rational(q) => Number::from_big(q.floor()),
+            n => n.clone(),
+        }
+    }
+
+    /// Rounds to the nearest integer, halves to even. Reals stay real.
+    pub fn round(&self) -> Number {
+        match self {
+            Number::Real(r) => Number::Real(r.round_ties_even()),
+            Number::Rational(q) => Number::from_big(q.round()),
+            n => n.clone(),
+        }
+    }
+
+    fn map_exact(&self, f: fn(&Rational) -> Number) -> Result<Number, EvalError> {
+        match self {
+            Number::Real(_) => match self.to_exact() {
+                Some(n) => Ok(Number::Real(f(&n.to_rational()).to_real())),
+                None => Err(EvalError::TypeError(String::from(
+                    "expected a finite number",
+                ))),
+            },
+            n => Ok(f(&n.to_rational())),
+        }
+    }
+}
+
+/// Prints a real so that it reads back as a real, never as an integer.
+pub fn write_real(r: f64) -> String {
+    if r.is_nan() {
+        String::from("+nan.0")
+    } else if r.is_infinite() {
+        String::from(if r > 0.0 { "+inf.0" } else { "-inf.0" })
+    } else {
+        format!("{:?}", r)
+    }
+}
+
+/// Parses a numeric literal: optional `#x`/`#b`/`#o`/`#d` radix and `#e`/`#i`
+/// exactness prefixes, an optional sign, then digits that may be separated by
+/// single `_`s, or two such digit strings separated by `/` for a rational.
+/// Reals are only accepted in radix 10.
+pub fn parse_number(text: &str) -> Result<Number, ParseErrorType> {
+    let mut radix = None;
+    let mut exact = None;
+    let mut rest = text;
+    while let Some(prefix) = rest.strip_prefix('#') {
+        let mut chars = prefix.chars();
+        match chars.next().map(|c| c.to_ascii_lowercase()) {
+            Some('x') if radix.is_none() => radix = Some(16),
+            Some('b') if radix.is_none() => radix = Some(2),
+            Some('o') if radix.is_none() => radix = Some(8),
+            Some('d') if radix.is_none() => radix = Some(10),
+            Some('e') if exact.is_none() => exact = Some(true),
+            Some('i') if exact.is_none() => exact = Some(false),
+            _ => return Err(ParseErrorType::MalformedNumber),
+        }
+        rest = chars.as_str();
+    }
+
+    let number = match rest {
+        "+inf.0" => Number::Real(f64::INFINITY),
+        "-inf.0" => Number::Real(f64::NEG_INFINITY),
+        "+nan.0" | "-nan.0" => Number::Real(f64::NAN),
+        _ => parse_signed(rest, radix.unwrap_or(10))?,
+    };
+    match (exact, number) {
+        (Some(false), n) => Ok(Number::Real(n.to_real())),
+        (Some(true), n) => n.to_exact().ok_or(ParseErrorType::NoExactRepresentation),
+        (_, n) => Ok(n),
+    }
+}
+
+fn parse_signed(text: &str, radix: u32) -> Result<Number, ParseErrorType> {
+    let (negative, body) = match text.strip_prefix('-') {
+        Some(body) => (true, body),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let body = strip_separators(body, radix)?;
+    if body.is_empty() {
+        return Err(ParseErrorType::MalformedNumber);
+    }
+    if let Some((num, den)) = body.split_once('/') {
+        return parse_rational(num, den, radix, negative);
+    }
+    if radix == 10 && body.contains(['.', 'e', 'E']) {
+        parse_real(&body, negative)
+    } else {
+        parse_integer(&body, radix, negative)
+    }
+}
+
+/// Removes `_` digit separators, which are only allowed between two digits.
+fn strip_separators(body: &str, radix: u32) -> Result<String, ParseErrorType> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut result = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if *c != '_' {
+            result.push(*c);
+            continue;
+        }
+        let before = i > 0 && chars[i - 1].is_digit(radix);
+        let after = chars.get(i + 1).is_some_and(|c| c.is_digit(radix));
+        if !before || !after {
+            return Err(ParseErrorType::MalformedNumber);
+        }
+    }
+    Ok(result)
+}
+
+fn parse_integer(body: &str, radix: u32, negative: bool) -> Result<Number, ParseErrorType> {
+    if let Some(c) = body.chars().find(|c| !c.is_digit(radix)) {
+        return Err(ParseErrorType::InvalidDigit(c, radix));
+    }
+    let mut value: i64 = 0;
+    for c in body.chars() {
+        let digit = c.to_digit(radix).unwrap() as i64;
+        let next = value.checked_mul(radix as i64).and_then(|v| {
+            if negative {
+                v.checked_sub(digit)
+            } else {
+                v.checked_add(digit)
+            }
+        });
+        match next {
+            Some(v) => value = v,
+            None => return Ok(Number::Big(BigInt::parse(body, radix, negative))),
+        }
+    }
+    Ok(Number::Integer(value))
+}
+
+fn parse_rational(
+    num: &str,
+    den: &str,
+    radix: u32,
+    negative: bool,
+) -> Result<Number, ParseErrorType> {
+    if num.is_empty() || den.is_empty() {
+        return Err(ParseErrorType::MalformedNumber);
+    }
+    let num = parse_integer(num, radix, negative)?;
+    let den = parse_integer(den, radix, false)?;
+    num.div(&den).map_err(|_| ParseErrorType::ZeroDenominator)
+}
+
+fn parse_real(body: &str, negative: bool) -> Result<Number, ParseErrorType> {
+    if let Some(c) = body
+        .chars()
+        .find(|c| !c.is_ascii_digit() && !matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+    {
+        return Err(ParseErrorType::InvalidDigit(c, 10));
+    }
+    match body.parse::<f64>() {
+        Ok(r) if negative => Ok(Number::Real(-r)),
+        Ok(r) => Ok(Number::Real(r)),
+        Err(_) => Err(ParseErrorType::MalformedNumber),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(text: &str) -> String {
+        match parse_number(text) {
+            Ok(Number::Integer(i)) => i.to_string(),
+            Ok(Number::Big(b)) => format!("big {}", b),
+            Ok(Number::Rational(q)) => q.to_string(),
+            Ok(Number::Real(r)) => write_real(r),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    #[test]
+    fn radix_prefixes() {
+        assert_eq!(show("#xff"), "255");
+        assert_eq!(show("#XFF"), "255");
+        assert_eq!(show("#b-101"), "-5");
+        assert_eq!(show("#o17"), "15");
+        assert_eq!(show("#d10"), "10");
+        assert_eq!(show("#x1/a"), "1/10");
+        assert_eq!(show("#x10000000000000000"), "big 18446744073709551616");
+    }
+
+    #[test]
+    fn exactness_prefixes() {
+        assert_eq!(show("#e1.5"), "3/2");
+        assert_eq!(show("#e1e3"), "1000");
+        assert_eq!(show("#i3/4"), "0.75");
+        assert_eq!(show("#e#x10"), "16");
+        assert_eq!(show("#x#i10"), "16.0");
+        assert_eq!(
+            show("#e+inf.0"),
+            "error: number has no exact representation"
+        );
+    }
+
+    #[test]
+    fn malformed_prefixes() {
+        for text in ["#x#x1", "#e#i1", "#q1", "#", "#e#λ"] {
+            assert_eq!(show(text), "error: malformed number", "{}", text);
+        }
+        assert_eq!(show("#xg"), "error: invalid digit `g` for radix 16");
+    }
+}
@@ -0,0 +1,416 @@
+use std::cmp::Ordering;
+
+/// An arbitrary-precision integer: a sign and a magnitude in base 2^32, least
+/// significant limb first, without leading zero limbs. Zero has an empty
+/// magnitude and is never negative, so equal values compare and hash equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    mag: Vec<u32>,
+}
+
+fn trim(mut mag: Vec<u32>) -> Vec<u32> {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    mag
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    result.push(carry as u32);
+    trim(result)
+}
+
+/// `a - b`, where `a` must not be smaller than `b`.
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, x) in a.iter().enumerate() {
+        let mut diff = *x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        result.push(diff as u32);
+    }
+    trim(result)
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let t = *x as u64 * *y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    trim(result)
+}
+
+fn divrem_small(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let cur = (rem << 32) | a[i] as u64;
+        quotient[i] = (cur / d as u64) as u32;
+        rem = cur % d as u64;
+    }
+    (trim(quotient), rem as u32)
+}
+
+/// Binary long division of magnitudes; `b` must not be zero.
+fn divrem_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let (q, r) = divrem_small(a, b[0]);
+        return (q, trim(vec![r]));
+    }
+    let mut quotient = vec![0u32; a.len()];
+    let mut rem: Vec<u32> = vec![];
+    for i in (0..a.len() * 32).rev() {
+        rem = shl_mag(&rem, 1);
+        if a[i / 32] >> (i % 32) & 1 == 1 {
+            if rem.is_empty() {
+                rem.push(1);
+            } else {
+                rem[0] |= 1;
+            }
+        }
+        if cmp_mag(&rem, b) != Ordering::Less {
+            rem = sub_mag(&rem, b);
+            quotient[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (trim(quotient), rem)
+}
+
+fn shl_mag(a: &[u32], bits: usize) -> Vec<u32> {
+    if a.is_empty() {
+        return vec![];
+    }
+    let mut result = vec![0u32; bits / 32];
+    let shift = bits % 32;
+    let mut carry = 0u32;
+    for x in a {
+        if shift == 0 {
+            result.push(*x);
+        } else {
+            result.push((x << shift) | carry);
+            carry = x >> (32 - shift);
+        }
+    }
+    result.push(carry);
+    trim(result)
+}
+
+impl BigInt {
+    fn new(negative: bool, mag: Vec<u32>) -> Self {
+        let mag = trim(mag);
+        Self {
+            negative: negative && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    /// Builds a number from its sign and limbs, least significant first.
+    pub fn from_parts(negative: bool, limbs: Vec<u32>) -> Self {
+        Self::new(negative, limbs)
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn limbs(&self) -> &[u32] {
+        &self.mag
+    }
+
+    pub fn one() -> Self {
+        Self::new(false, vec![1])
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::new(false, self.mag.clone())
+    }
+
+    pub fn is_even(&self) -> bool {
+        self.mag.first().is_none_or(|limb| limb & 1 == 0)
+    }
+
+    /// Greatest common divisor, always non-negative.
+    pub fn gcd(&self, other: &Self) -> Self {
+        let mut a = self.abs();
+        let mut b = other.abs();
+        while !b.is_zero() {
+            let (_, r) = a.div_rem(&b).unwrap();
+            a = b;
+            b = r;
+        }
+        a
+    }
+
+    pub fn from_i64(i: i64) -> Self {
+        let m = i.unsigned_abs();
+        Self::new(i < 0, vec![m as u32, (m >> 32) as u32])
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let m = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0u64, |acc, limb| (acc << 32) | *limb as u64);
+        if self.negative {
+            0i64.checked_sub_unsigned(m)
+        } else {
+            i64::try_from(m).ok()
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let m = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0.0, |acc, limb| acc * 4294967296.0 + *limb as f64);
+        if self.negative { -m } else { m }
+    }
+
+    /// The integer equal to `r`, which must be finite and whole.
+    pub fn from_f64(r: f64) -> Option<Self> {
+        if !r.is_finite() || r.fract() != 0.0 {
+            return None;
+        }
+        let bits = r.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i64;
+        if exponent == 0 {
+            return Some(Self::new(false, vec![]));
+        }
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let shift = exponent - 1075;
+        let mag = if shift >= 0 {
+            shl_mag(&[mantissa as u32, (mantissa >> 32) as u32], shift as usize)
+        } else {
+            let m = mantissa >> -shift;
+            vec![m as u32, (m >> 32) as u32]
+        };
+        Some(Self::new(r < 0.0, mag))
+    }
+
+    /// Parses digits that have already been checked against `radix`.
+    pub fn parse(digits: &str, radix: u32, negative: bool) -> Self {
+        let mut mag: Vec<u32> = vec![];
+        for c in digits.chars() {
+            let digit = c.to_digit(radix).expect("digit checked by the caller");
+            mag = add_mag(&mul_mag(&mag, &[radix]), &[digit]);
+        }
+        Self::new(negative, mag)
+    }
+
+    /// The number of bits in the magnitude, 0 for zero.
+    pub fn bits(&self) -> usize {
+        match self.mag.last() {
+            Some(top) => 32 * self.mag.len() - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    /// `self * 2^bits`.
+    pub fn shl(&self, bits: usize) -> Self {
+        Self::new(self.negative, shl_mag(&self.mag, bits))
+    }
+
+    pub fn neg(&self) -> Self {
+        Self::new(!self.negative, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        if self.negative == other.negative {
+            return Self::new(self.negative, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => Self::new(other.negative, sub_mag(&other.mag, &self.mag)),
+            _ => Self::new(self.negative, sub_mag(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        Self::new(
+            self.negative != other.negative,
+            mul_mag(&self.mag, &other.mag),
+        )
+    }
+
+    /// Quotient rounded toward zero and the remainder, which has the sign of
+    /// `self`. `None` when dividing by zero.
+    pub fn div_rem(&self, other: &Self) -> Option<(Self, Self)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = divrem_mag(&self.mag, &other.mag);
+        Some((
+            Self::new(self.negative != other.negative, q),
+            Self::new(self.negative, r),
+        ))
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_zero() {
+            return write!(fmt, "0");
+        }
+        let mut chunks = vec![];
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let (q, r) = divrem_small(&mag, 1_000_000_000);
+            chunks.push(r);
+            mag = q;
+        }
+        if self.negative {
+            write!(fmt, "-")?;
+        }
+        write!(fmt, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(fmt, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(i: i128) -> BigInt {
+        BigInt::parse(&i.unsigned_abs().to_string(), 10, i < 0)
+    }
+
+    /// Values around the limb boundaries, where carries and borrows happen.
+    const EDGES: &[i128] = &[
+        0,
+        1,
+        2,
+        7,
+        0xffff_ffff,
+        0x1_0000_0000,
+        0x1_0000_0001,
+        0xffff_ffff_ffff_ffff,
+        0x1_0000_0000_0000_0000,
+        0x1_0000_0000_ffff_ffff_0000_0000,
+        0xffff_ffff_ffff_ffff_ffff_ffff,
+        1 << 100,
+    ];
+
+    fn edges() -> impl Iterator<Item = i128> {
+        EDGES.iter().flat_map(|i| [*i, -*i])
+    }
+
+    #[test]
+    fn add_sub_mul_match_i128() {
+        for a in edges() {
+            for b in edges() {
+                assert_eq!(big(a).add(&big(b)), big(a + b), "{} + {}", a, b);
+                assert_eq!(big(a).sub(&big(b)), big(a - b), "{} - {}", a, b);
+                if let Some(product) = a.checked_mul(b) {
+                    assert_eq!(big(a).mul(&big(b)), big(product), "{} * {}", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn div_rem_truncates_toward_zero() {
+        for (a, b, q, r) in [
+            (7, 2, 3, 1),
+            (-7, 2, -3, -1),
+            (7, -2, -3, 1),
+            (-7, -2, 3, -1),
+        ] {
+            assert_eq!(big(a).div_rem(&big(b)), Some((big(q), big(r))));
+        }
+        for a in edges() {
+            for b in edges().filter(|b| *b != 0) {
+                let (q, r) = big(a).div_rem(&big(b)).unwrap();
+                assert_eq!((q, r), (big(a / b), big(a % b)), "{} / {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn div_rem_by_zero() {
+        assert_eq!(big(5).div_rem(&big(0)), None);
+    }
+
+    #[test]
+    fn zero_is_never_negative() {
+        assert_eq!(big(-5).add(&big(5)), big(0));
+        assert!(!big(5).sub(&big(5)).is_negative());
+        assert_eq!(big(-3).div_rem(&big(7)).unwrap().0.to_string(), "0");
+    }
+
+    #[test]
+    fn display_pads_inner_chunks() {
+        assert_eq!(
+            big(1_000_000_000_000_000_005).to_string(),
+            "1000000000000000005"
+        );
+        assert_eq!(
+            big(-(1 << 100)).to_string(),
+            "-1267650600228229401496703205376"
+        );
+    }
+
+    #[test]
+    fn i64_round_trip() {
+        for i in [0, 1, -1, i64::MAX, i64::MIN] {
+            assert_eq!(BigInt::from_i64(i).to_i64(), Some(i));
+        }
+        assert_eq!(big(i64::MAX as i128 + 1).to_i64(), None);
+        assert_eq!(big(i64::MIN as i128 - 1).to_i64(), None);
+    }
+}
@@ -0,0 +1,251 @@
+use super::bigint::BigInt;
+
+/// An exact fraction in lowest terms. The denominator is always positive, so
+/// the sign lives in the numerator.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Rational {
+    num: BigInt,
+    den: BigInt,
+}
+
+impl Rational {
+    /// `num/den` normalized, or `None` when `den` is zero.
+    pub fn new(num: BigInt, den: BigInt) -> Option<Self> {
+        if den.is_zero() {
+            return None;
+        }
+        let gcd = num.gcd(&den);
+        let (mut num, _) = num.div_rem(&gcd)?;
+        let (mut den, _) = den.div_rem(&gcd)?;
+        if den.is_negative() {
+            num = num.neg();
+            den = den.neg();
+        }
+        Some(Self { num, den })
+    }
+
+    pub fn from_integer(i: BigInt) -> Self {
+        Self {
+            num: i,
+            den: BigInt::one(),
+        }
+    }
+
+    /// The exact value of `r`, which must be finite. A fractional double is
+    /// its 53-bit mantissa over a power of two.
+    pub fn from_f64(r: f64) -> Option<Self> {
+        if !r.is_finite() {
+            return None;
+        }
+        if r.fract() == 0.0 {
+            return BigInt::from_f64(r).map(Self::from_integer);
+        }
+        let bits = r.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i64;
+        let mut mantissa = bits & ((1 << 52) - 1);
+        let shift = if exponent == 0 {
+            1074
+        } else {
+            mantissa |= 1 << 52;
+            1075 - exponent
+        };
+        let num = BigInt::from_parts(r < 0.0, vec![mantissa as u32, (mantissa >> 32) as u32]);
+        let two = BigInt::from_i64(2);
+        let mut den = BigInt::one();
+        for _ in 0..shift {
+            den = den.mul(&two);
+        }
+        Self::new(num, den)
+    }
+
+    pub fn numerator(&self) -> &BigInt {
+        &self.num
+    }
+
+    pub fn denominator(&self) -> &BigInt {
+        &self.den
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.den == BigInt::one()
+    }
+
+    /// The closest double. Converting the numerator and denominator on
+    /// their own would overflow when they are too large for a double even
+    /// if their quotient is not, so this divides them exactly with 65 bits
+    /// of quotient, keeps whether anything was left over in one more bit so
+    /// it still rounds right, and scales the result back.
+    pub fn to_f64(&self) -> f64 {
+        let shift = 65 - (self.num.bits() as i64 - self.den.bits() as i64);
+        let (num, den) = if shift >= 0 {
+            (self.num.shl(shift as usize), self.den.clone())
+        } else {
+            (self.num.clone(), self.den.shl(-shift as usize))
+        };
+        let (q, r) = num.div_rem(&den).unwrap();
+        let sticky = match (r.is_zero(), r.is_negative()) {
+            (true, _) => 0,
+            (false, true) => -1,
+            (false, false) => 1,
+        };
+        let q = q.shl(1).add(&BigInt::from_i64(sticky));
+        scale(q.to_f64(), -(shift + 1))
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        let num = self.num.mul(&other.den).add(&other.num.mul(&self.den));
+        Self::new(num, self.den.mul(&other.den)).unwrap()
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        let num = self.num.mul(&other.den).sub(&other.num.mul(&self.den));
+        Self::new(num, self.den.mul(&other.den)).unwrap()
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        Self::new(self.num.mul(&other.num), self.den.mul(&other.den)).unwrap()
+    }
+
+    /// `None` when `other` is zero.
+    pub fn div(&self, other: &Self) -> Option<Self> {
+        Self::new(self.num.mul(&other.den), self.den.mul(&other.num))
+    }
+
+    /// The largest integer not greater than `self`.
+    pub fn floor(&self) -> BigInt {
+        let (q, r) = self.num.div_rem(&self.den).unwrap();
+        if r.is_negative() {
+            q.sub(&BigInt::one())
+        } else {
+            q
+        }
+    }
+
+    /// The closest integer to `self`, rounding halves to even.
+    pub fn round(&self) -> BigInt {
+        let floor = self.floor();
+        let twice_frac = self
+            .num
+            .sub(&floor.mul(&self.den))
+            .mul(&BigInt::from_i64(2));
+        match twice_frac.cmp(&self.den) {
+            std::cmp::Ordering::Less => floor,
+            std::cmp::Ordering::Greater => floor.add(&BigInt::one()),
+            std::cmp::Ordering::Equal if floor.is_even() => floor,
+            std::cmp::Ordering::Equal => floor.add(&BigInt::one()),
+        }
+    }
+}
+
+/// `x * 2^exp`, in steps small enough for `powi`.
+fn scale(mut x: f64, mut exp: i64) -> f64 {
+    while exp > 1000 && x.is_finite() && x != 0.0 {
+        x *= 2f64.powi(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 && x.is_finite() && x != 0.0 {
+        x *= 2f64.powi(-1000);
+        exp += 1000;
+    }
+    x * 2f64.powi(exp.clamp(-2000, 2000) as i32)
+}
+
+impl std::fmt::Display for Rational {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}/{}", self.num, self.den)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q(num: i64, den: i64) -> Rational {
+        Rational::new(BigInt::from_i64(num), BigInt::from_i64(den)).unwrap()
+    }
+
+    #[test]
+    fn new_reduces_to_lowest_terms() {
+        assert_eq!(q(6, 4).to_string(), "3/2");
+        assert_eq!(q(0, 5).to_string(), "0/1");
+        assert_eq!(q(7, 1).to_string(), "7/1");
+        assert!(q(8, 4).is_integer());
+    }
+
+    #[test]
+    fn new_moves_the_sign_to_the_numerator() {
+        assert_eq!(q(6, -4).to_string(), "-3/2");
+        assert_eq!(q(-6, -4).to_string(), "3/2");
+        assert_eq!(q(-6, 4), q(6, -4));
+        assert!(!q(1, -3).denominator().is_negative());
+    }
+
+    #[test]
+    fn zero_denominator() {
+        assert_eq!(Rational::new(BigInt::one(), BigInt::from_i64(0)), None);
+        assert_eq!(q(1, 2).div(&q(0, 1)), None);
+    }
+
+    #[test]
+    fn arithmetic_stays_normalized() {
+        assert_eq!(q(1, 2).add(&q(1, 3)), q(5, 6));
+        assert_eq!(q(1, 2).sub(&q(1, 2)), q(0, 1));
+        assert_eq!(q(-2, 3).mul(&q(3, 4)), q(-1, 2));
+        assert_eq!(q(1, 2).div(&q(-1, 4)), Some(q(-2, 1)));
+    }
+
+    #[test]
+    fn floor_and_round() {
+        let cases = [
+            (7, 2, 3, 4),
+            (-7, 2, -4, -4),
+            (5, 2, 2, 2),
+            (-5, 2, -3, -2),
+            (5, 3, 1, 2),
+        ];
+        for (num, den, floor, round) in cases {
+            assert_eq!(
+                q(num, den).floor(),
+                BigInt::from_i64(floor),
+                "{}/{}",
+                num,
+                den
+            );
+            assert_eq!(
+                q(num, den).round(),
+                BigInt::from_i64(round),
+                "{}/{}",
+                num,
+                den
+            );
+        }
+    }
+
+    #[test]
+    fn from_f64_is_exact() {
+        assert_eq!(Rational::from_f64(0.75), Some(q(3, 4)));
+        assert_eq!(Rational::from_f64(-2.0), Some(q(-2, 1)));
+        assert_eq!(Rational::from_f64(f64::NAN), None);
+        let tenth = Rational::from_f64(0.1).unwrap();
+        assert_eq!(tenth.denominator().to_string(), "36028797018963968");
+        assert_eq!(tenth.to_f64(), 0.1);
+    }
+
+    #[test]
+    fn to_f64_beyond_double_range() {
+        let ten = BigInt::from_i64(10);
+        let mut big = BigInt::one();
+        for _ in 0..399 {
+            big = big.mul(&ten);
+        }
+        let r = Rational::new(big.mul(&ten).add(&BigInt::one()), big.clone()).unwrap();
+        assert_eq!(r.to_f64(), 10.0);
+        let r = Rational::new(BigInt::one().neg(), big.clone()).unwrap();
+        assert_eq!(r.to_f64(), -0.0);
+        let r = Rational::new(big.clone(), BigInt::from_i64(3)).unwrap();
+        assert_eq!(r.to_f64(), f64::INFINITY);
+        for (num, den) in [(1, 3), (-2, 7), (355, 113), (1, 1 << 40), (123456789, 1000)] {
+            assert_eq!(q(num, den).to_f64(), num as f64 / den as f64);
+        }
+    }
+}
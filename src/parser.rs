@@ -1,89 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
 use crate::context::Context;
 use crate::context::gc_heap::Handle;
+use crate::context::roots::Root;
+use crate::context::spans::{SourceFile, Span};
+use crate::evaluator::Evaluator;
 use crate::lexer::{Lexer, Token, TokenType};
+use crate::number::parse_number;
 use crate::sexp::Sexp;
+use crate::sexp::hash_table::{HashTable, TableKind};
+use crate::sexp::port::Port;
 
 #[derive(Debug)]
 pub enum ParseErrorType {
     StringNotTerminated,
-    FailedToParseInteger,
+    InvalidEscape,
+    CommentNotTerminated,
+    InvalidDigit(char, u32),
+    MalformedNumber,
+    NoExactRepresentation,
+    ZeroDenominator,
+    InvalidCharacter,
+    MalformedHashTable,
     UnexpectedRPAREN,
     UnexpectedEOF,
     MalformedList,
+    ReaderMacro(String),
+    UndefinedLabel(u64),
+    DuplicateLabel(u64),
 }
 
 impl std::fmt::Display for ParseErrorType {
-    fn fmt(self: &Self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::StringNotTerminated => write!(fmt, "string not terminated"),
-            Self::FailedToParseInteger => write!(fmt, "failed to parse integer"),
+            Self::InvalidEscape => write!(fmt, "invalid escape sequence"),
+            Self::CommentNotTerminated => write!(fmt, "block comment not terminated"),
+            Self::InvalidDigit(c, radix) => {
+                write!(fmt, "invalid digit `{}` for radix {}", c, radix)
+            }
+            Self::MalformedNumber => write!(fmt, "malformed number"),
+            Self::NoExactRepresentation => write!(fmt, "number has no exact representation"),
+            Self::ZeroDenominator => write!(fmt, "rational with a zero denominator"),
+            Self::InvalidCharacter => write!(fmt, "invalid character literal"),
+            Self::MalformedHashTable => write!(fmt, "malformed hash table"),
             Self::UnexpectedRPAREN => write!(fmt, "unexpected `)`"),
             Self::UnexpectedEOF => write!(fmt, "unexpected end of file"),
             Self::MalformedList => write!(fmt, "malformed list"),
+            Self::ReaderMacro(e) => write!(fmt, "reader macro failed: {}", e),
+            Self::UndefinedLabel(n) => write!(fmt, "undefined datum label `#{}#`", n),
+            Self::DuplicateLabel(n) => write!(fmt, "datum label `#{}=` defined twice", n),
         }
     }
 }
 
+/// Something that helps explain a parse error, with the bytes `pos..end` it
+/// refers to.
+#[derive(Debug)]
+pub struct Note {
+    pub pos: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+/// A syntax error in the bytes `pos..end` of the source.
 #[derive(Debug)]
 pub struct ParseError {
     pub r#type: ParseErrorType,
     pub pos: usize,
+    pub end: usize,
+    pub notes: Vec<Note>,
 }
 
 impl ParseError {
-    pub fn to_string(self: &Self, file_path: &String, source: &String) -> String {
-        let mut line: usize = 1;
-        let mut column: usize = 1;
-        for i in 0..self.pos {
-            if i >= source.len() {
-                unreachable!()
-            }
-            if source.as_bytes()[i] == b'\n' {
-                line += 1;
-                column = 1;
-            } else {
-                column += 1;
-            }
+    pub fn new(r#type: ParseErrorType, pos: usize, end: usize) -> Self {
+        Self {
+            r#type,
+            pos,
+            end,
+            notes: vec![],
         }
-        format!("{}:{}:{}: {}", file_path, line, column, self.r#type)
     }
+
+    /// Prints the error and its notes as `file:line:column: message`, each
+    /// followed by the line it points at with the span underlined.
+    pub fn to_string(&self, source: &SourceFile) -> String {
+        let mut result = describe(source, self.pos, self.end, &self.r#type.to_string());
+        for note in &self.notes {
+            let message = format!("note: {}", note.message);
+            result.push('\n');
+            result.push_str(&describe(source, note.pos, note.end, &message));
+        }
+        result
+    }
+}
+
+fn describe(source: &SourceFile, pos: usize, end: usize, message: &str) -> String {
+    let (line, column) = source.line_column(pos);
+    let (end_line, end_column) = source.line_column(end);
+    let text = source.line_text(line);
+    let width = if end_line == line {
+        end_column.saturating_sub(column).max(1)
+    } else {
+        (text.chars().count() + 1).saturating_sub(column).max(1)
+    };
+    let indent: String = text
+        .chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{}:{}:{}: {}\n    {}\n    {}{}",
+        source.name(),
+        line,
+        column,
+        message,
+        text,
+        indent,
+        "^".repeat(width)
+    )
 }
 
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
     look: Option<Token>,
+    source: &'a Rc<SourceFile>,
+    /// The byte offset just past the last token consumed.
+    end: usize,
+    /// The datums labelled with `#n=` in the current top-level form.
+    labels: HashMap<u64, Root>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(source: &'a String) -> Self {
-        return Self {
-            lexer: Lexer::new(source),
+    pub fn new(source: &'a Rc<SourceFile>) -> Self {
+        Self {
+            lexer: Lexer::new(source.text()),
             look: None,
-        };
+            source,
+            end: 0,
+            labels: HashMap::new(),
+        }
+    }
+
+    /// A parser that reads from `port`'s text at the port's position.
+    pub fn for_port(port: &'a Port) -> Self {
+        let mut parser = Self::new(port.source());
+        parser.lexer.set_pos(port.pos());
+        parser
+    }
+
+    /// The byte offset just past the last form read.
+    pub fn pos(&self) -> usize {
+        match &self.look {
+            Some(t) => t.pos,
+            None => self.lexer.pos(),
+        }
     }
 
-    fn advance(self: &mut Self) -> Result<(), ParseError> {
-        let t = self.lexer.next_token()?;
+    fn advance(&mut self, ctx: &Context) -> Result<(), ParseError> {
+        self.end = self.lexer.pos();
+        let t = self.lexer.next_token(&ctx.readtable)?;
         self.look = t;
         Ok(())
     }
 
-    fn make_error(self: &Self, r#type: ParseErrorType) -> Result<Handle, ParseError> {
-        return Err(ParseError {
-            r#type: r#type,
-            pos: if let Some(t) = &self.look { t.pos } else { 0 },
-        });
+    /// Records that the pair `h` was read from `start..end`. Other data
+    /// have no span.
+    fn record_span(&self, ctx: &mut Context, h: Handle, start: usize, end: usize) {
+        if let Sexp::Pair(_, _) = ctx.heap.get_ref(h).as_ref() {
+            ctx.spans.insert(h, Span::new(self.source, start, end));
+        }
+    }
+
+    /// An error on the lookahead, or at the end of the source if there is
+    /// none.
+    fn error(&self, r#type: ParseErrorType) -> ParseError {
+        let end = self.lexer.pos();
+        match &self.look {
+            Some(t) => ParseError::new(r#type, t.pos, end),
+            None => ParseError::new(r#type, end, end),
+        }
+    }
+
+    fn make_error(&self, r#type: ParseErrorType) -> Result<Handle, ParseError> {
+        Err(self.error(r#type))
+    }
+
+    /// Skips the forms that follow any `#;` in front of the lookahead.
+    fn skip_datum_comments(
+        &mut self,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<(), ParseError> {
+        while let Some(t) = &self.look {
+            if t.r#type != TokenType::DATUM_COMMENT {
+                break;
+            }
+            self.advance(ctx)?;
+            if self.datum(ctx, e)?.is_none() {
+                self.make_error(ParseErrorType::UnexpectedEOF)?;
+            }
+        }
+        Ok(())
     }
 
-    fn parse_cdr(self: &mut Self, ctx: &mut Context) -> Result<Handle, ParseError> {
+    fn parse_cdr(&mut self, ctx: &mut Context, e: &mut Evaluator) -> Result<Handle, ParseError> {
+        self.skip_datum_comments(ctx, e)?;
         match &self.look {
             None => self.make_error(ParseErrorType::UnexpectedEOF),
             Some(t) => match t.r#type {
-                TokenType::RPAREN => Ok(ctx.heap.alloc(Sexp::Nil)),
+                TokenType::RPAREN => Ok(Handle::Nil),
                 TokenType::DOT => {
-                    self.advance()?;
-                    let form = self.next_form(ctx)?;
+                    self.advance(ctx)?;
+                    let form = self.datum(ctx, e)?;
                     if let Some(cdr) = form {
+                        self.skip_datum_comments(ctx, e)?;
                         if let Some(t) = &self.look {
                             if t.r#type != TokenType::RPAREN {
                                 self.make_error(ParseErrorType::MalformedList)
@@ -98,10 +230,16 @@ impl<'a> Parser<'a> {
                     }
                 }
                 _ => {
-                    let form = self.next_form(ctx)?;
+                    let start = t.pos;
+                    let form = self.datum(ctx, e)?;
                     if let Some(car) = form {
-                        let cdr = self.parse_cdr(ctx)?;
-                        Ok(ctx.heap.alloc(Sexp::Pair(car, cdr)))
+                        let _car = ctx.root(car);
+                        let cdr = self.parse_cdr(ctx, e)?;
+                        let pair = ctx.heap.alloc(Sexp::Pair(car, cdr));
+                        // The lookahead is the closing `)`.
+                        let end = self.pos() + 1;
+                        self.record_span(ctx, pair, start, end);
+                        Ok(pair)
                     } else {
                         self.make_error(ParseErrorType::UnexpectedEOF)
                     }
@@ -110,56 +248,508 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_list(self: &mut Self, ctx: &mut Context) -> Result<Handle, ParseError> {
-        self.advance()?; // skip the '('
-        let first = if let Some(s) = self.next_form(ctx)? {
-            s
-        } else {
-            self.make_error(ParseErrorType::UnexpectedEOF)?
+    fn parse_list(&mut self, ctx: &mut Context, e: &mut Evaluator) -> Result<Handle, ParseError> {
+        let start = self.pos();
+        self.advance(ctx)?; // skip the '('
+        self.skip_datum_comments(ctx, e)?;
+        if let Some(t) = &self.look
+            && t.r#type == TokenType::DOT
+        {
+            self.make_error(ParseErrorType::MalformedList)?;
+        }
+        let result = self.parse_cdr(ctx, e)?;
+        self.advance(ctx)?; // skip the ')'
+        self.record_span(ctx, result, start, self.end);
+        Ok(result)
+    }
+
+    /// Reads the elements of a `#( ... )` literal.
+    fn parse_vector(&mut self, ctx: &mut Context, e: &mut Evaluator) -> Result<Handle, ParseError> {
+        self.advance(ctx)?; // skip the '#('
+        let mut elements = vec![];
+        let mut roots = vec![];
+        loop {
+            self.skip_datum_comments(ctx, e)?;
+            match &self.look {
+                None => return self.make_error(ParseErrorType::UnexpectedEOF),
+                Some(t) if t.r#type == TokenType::RPAREN => break,
+                Some(t) if t.r#type == TokenType::DOT => {
+                    return self.make_error(ParseErrorType::MalformedList);
+                }
+                Some(_) => {}
+            }
+            match self.datum(ctx, e)? {
+                Some(form) => {
+                    roots.push(ctx.root(form));
+                    elements.push(form);
+                }
+                None => return self.make_error(ParseErrorType::UnexpectedEOF),
+            }
+        }
+        self.advance(ctx)?; // skip the ')'
+        Ok(ctx.heap.alloc(Sexp::Vector(elements)))
+    }
+
+    /// Reads the `(key . value)` entries of a `#hash( ... )` literal. Later
+    /// entries replace earlier ones with the same key.
+    fn parse_hash_table(
+        &mut self,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<Handle, ParseError> {
+        let kind = match self.look.as_ref().map(|t| t.val.as_str()) {
+            Some("#hasheq(") => TableKind::Eq,
+            Some("#hasheqv(") => TableKind::Eqv,
+            _ => TableKind::Equal,
+        };
+        self.advance(ctx)?; // skip the '#hash('
+        let mut table = HashTable::new(kind);
+        let mut roots = vec![];
+        loop {
+            self.skip_datum_comments(ctx, e)?;
+            match &self.look {
+                None => return self.make_error(ParseErrorType::UnexpectedEOF),
+                Some(t) if t.r#type == TokenType::RPAREN => break,
+                Some(_) => {}
+            }
+            let entry = match self.datum(ctx, e)? {
+                Some(entry) => entry,
+                None => return self.make_error(ParseErrorType::UnexpectedEOF),
+            };
+            roots.push(ctx.root(entry));
+            let (key, val) = match ctx.heap.get_ref(entry).as_ref() {
+                Sexp::Pair(key, val) => (*key, *val),
+                _ => return self.make_error(ParseErrorType::MalformedHashTable),
+            };
+            let (hash, slot) = table.find(key, &ctx.heap);
+            table.insert(hash, slot, key, val);
+        }
+        self.advance(ctx)?; // skip the ')'
+        Ok(ctx.heap.alloc(Sexp::HashTable(table)))
+    }
+
+    /// Reads the form after a `'`, `` ` ``, `,` or `,@` and wraps it as
+    /// `(name form)`.
+    fn parse_abbreviation(
+        &mut self,
+        name: &str,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<Handle, ParseError> {
+        let start = self.pos();
+        self.advance(ctx)?; // skip the prefix
+        let form = match self.datum(ctx, e)? {
+            Some(form) => form,
+            None => return self.make_error(ParseErrorType::UnexpectedEOF),
+        };
+        let _form = ctx.root(form);
+        let rest = ctx.heap.alloc(Sexp::Pair(form, Handle::Nil));
+        self.record_span(ctx, rest, start, self.end);
+        let _rest = ctx.root(rest);
+        let name = Handle::Symbol(ctx.interner.intern(name));
+        let result = ctx.heap.alloc(Sexp::Pair(name, rest));
+        self.record_span(ctx, result, start, self.end);
+        Ok(result)
+    }
+
+    /// Reads the datum after a `#n=` label. References to the label from
+    /// inside the datum are read as a placeholder, an empty vector cell that
+    /// nothing else can refer to, which is replaced with the datum once it is
+    /// complete and then left for the collector.
+    fn parse_label(
+        &mut self,
+        n: u64,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<Handle, ParseError> {
+        if self.labels.contains_key(&n) {
+            return self.make_error(ParseErrorType::DuplicateLabel(n));
+        }
+        let start = self.pos();
+        let placeholder = ctx.heap.alloc(Sexp::Vector(vec![]));
+        self.labels.insert(n, ctx.root(placeholder));
+        self.advance(ctx)?; // skip the label
+        let datum = match self.datum(ctx, e)? {
+            Some(datum) => datum,
+            None => return self.make_error(ParseErrorType::UnexpectedEOF),
         };
-        let cdr = self.parse_cdr(ctx)?;
-        let result = ctx.heap.alloc(Sexp::Pair(first, cdr));
-        self.advance()?; // skip the ')'
+        if datum == placeholder {
+            return Err(ParseError::new(
+                ParseErrorType::UndefinedLabel(n),
+                start,
+                self.end,
+            ));
+        }
+        replace_placeholder(ctx, datum, placeholder);
+        self.labels.insert(n, ctx.root(datum));
+        Ok(datum)
+    }
+
+    /// Calls the reader macro `proc` with a port on the source at `start`,
+    /// just after the macro characters, and carries on where it stopped
+    /// reading. A pair the macro returns gets the span of the text it read,
+    /// unless it already has a span of its own.
+    fn read_macro(
+        &mut self,
+        proc: Handle,
+        start: usize,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<Handle, ParseError> {
+        let pos = self.pos();
+        let port = Port::new(self.source.clone(), start);
+        let port_h = ctx.heap.alloc(Sexp::Port(port.clone()));
+        let args = ctx.heap.alloc(Sexp::Pair(port_h, Handle::Nil));
+        let result = e.call(proc, args, ctx).map_err(|err| {
+            ParseError::new(ParseErrorType::ReaderMacro(err.to_string()), pos, start)
+        })?;
+        self.lexer.set_pos(port.pos());
+        self.advance(ctx)?;
+        if ctx.spans.get(result).is_none() {
+            self.record_span(ctx, result, pos, self.end);
+        }
         Ok(result)
     }
 
-    pub fn next_form(self: &mut Self, ctx: &mut Context) -> Result<Option<Handle>, ParseError> {
-        if self.look == None {
-            self.advance()?;
+    /// Notes where the list, vector or hash table at `pos..end` was opened
+    /// when `err` is running out of input before its `)`.
+    fn unclosed(&self, mut err: ParseError, pos: usize, end: usize) -> ParseError {
+        if let ParseErrorType::UnexpectedEOF = err.r#type {
+            err.notes.push(Note {
+                pos,
+                end,
+                message: format!("unclosed `{}` opened here", &self.source.text()[pos..end]),
+            });
+        }
+        err
+    }
+
+    /// Reads the next top-level form. The lookahead is lexed again first,
+    /// since evaluating the previous form may have changed the readtable.
+    pub fn next_form(
+        &mut self,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<Option<Handle>, ParseError> {
+        if let Some(t) = self.look.take() {
+            self.lexer.set_pos(t.pos);
         }
+        self.labels.clear();
+        let result = self.datum(ctx, e);
+        self.labels.clear();
+        result
+    }
+
+    /// Reads every remaining form and returns all the syntax errors in them.
+    /// After an error, reading carries on with the next top-level form.
+    pub fn check(&mut self, ctx: &mut Context, e: &mut Evaluator) -> Vec<ParseError> {
+        let mut errors = vec![];
+        loop {
+            let start = self.pos();
+            match self.next_form(ctx, e) {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(err) => {
+                    let eof = matches!(err.r#type, ParseErrorType::UnexpectedEOF);
+                    errors.push(err);
+                    if let Some(err) = self.skip_form(start, ctx)
+                        && !eof
+                    {
+                        errors.push(err);
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// Moves past the top-level form at `start`: to the `)` that balances
+    /// its first opening token, or past its first token if that is not one.
+    /// Tokens that don't lex count as atoms. Returns an `UnexpectedEOF` error
+    /// noting the lists still open if the input ends inside the form.
+    fn skip_form(&mut self, start: usize, ctx: &Context) -> Option<ParseError> {
+        self.look = None;
+        self.lexer.set_pos(start);
+        let mut open = vec![];
+        loop {
+            let pos = self.lexer.pos();
+            match self.lexer.next_token(&ctx.readtable) {
+                Ok(None) => break,
+                Ok(Some(t)) => match t.r#type {
+                    TokenType::LPAREN | TokenType::VECTOR_START | TokenType::HASH_TABLE_START => {
+                        open.push((t.pos, self.lexer.pos()))
+                    }
+                    TokenType::RPAREN => {
+                        open.pop();
+                    }
+                    TokenType::QUOTE
+                    | TokenType::QUASIQUOTE
+                    | TokenType::UNQUOTE
+                    | TokenType::UNQUOTE_SPLICING
+                    | TokenType::DATUM_COMMENT
+                    | TokenType::LABEL_DEF => continue,
+                    _ => {}
+                },
+                Err(_) if self.lexer.pos() == pos => {
+                    let c = self.source.text()[pos..].chars().next().unwrap();
+                    self.lexer.set_pos(pos + c.len_utf8());
+                }
+                Err(_) => {}
+            }
+            if open.is_empty() {
+                return None;
+            }
+        }
+        if open.is_empty() {
+            return None;
+        }
+        let pos = self.lexer.pos();
+        let err = ParseError::new(ParseErrorType::UnexpectedEOF, pos, pos);
+        Some(
+            open.into_iter()
+                .rev()
+                .fold(err, |err, (pos, end)| self.unclosed(err, pos, end)),
+        )
+    }
+
+    fn datum(
+        &mut self,
+        ctx: &mut Context,
+        e: &mut Evaluator,
+    ) -> Result<Option<Handle>, ParseError> {
+        if self.look.is_none() {
+            self.advance(ctx)?;
+        }
+        self.skip_datum_comments(ctx, e)?;
         match &self.look {
             None => Ok(None),
             Some(t) => match t.r#type {
-                TokenType::INTEGER => {
-                    let i = match t.val.parse::<i64>() {
-                        Ok(i) => i,
-                        Err(_) => {
-                            return Err(ParseError {
-                                r#type: ParseErrorType::FailedToParseInteger,
-                                pos: t.pos,
-                            });
-                        }
+                TokenType::INTEGER | TokenType::REAL | TokenType::RATIONAL => {
+                    let n = match parse_number(&t.val) {
+                        Ok(n) => n,
+                        Err(r#type) => return Err(self.error(r#type)),
                     };
-                    self.advance()?;
-                    Ok(Some(ctx.heap.alloc(Sexp::Integer(i))))
+                    self.advance(ctx)?;
+                    Ok(Some(n.into_handle(ctx)))
                 }
                 TokenType::SYMBOL => {
-                    let result = Sexp::Symbol(ctx.interner.intern(t.val.as_str()));
-                    self.advance()?;
-                    Ok(Some(ctx.heap.alloc(result)))
+                    let result = Handle::Symbol(ctx.interner.intern(t.val.as_str()));
+                    self.advance(ctx)?;
+                    Ok(Some(result))
                 }
                 TokenType::STRING => {
                     let result = Sexp::String(t.val.clone());
-                    self.advance()?;
+                    self.advance(ctx)?;
                     Ok(Some(ctx.heap.alloc(result)))
                 }
-                TokenType::LPAREN => Ok(Some(self.parse_list(ctx)?)),
-                TokenType::RPAREN => Err(ParseError {
-                    r#type: ParseErrorType::UnexpectedRPAREN,
-                    pos: t.pos,
-                }),
-                _ => todo!(),
+                TokenType::BOOLEAN => {
+                    let result = Handle::Boolean(t.val.starts_with("#t"));
+                    self.advance(ctx)?;
+                    Ok(Some(result))
+                }
+                TokenType::CHARACTER => {
+                    let result = Handle::Char(t.val.chars().next().unwrap());
+                    self.advance(ctx)?;
+                    Ok(Some(result))
+                }
+                TokenType::INERT => {
+                    self.advance(ctx)?;
+                    Ok(Some(Handle::Inert))
+                }
+                TokenType::IGNORE => {
+                    self.advance(ctx)?;
+                    Ok(Some(Handle::Ignore))
+                }
+                TokenType::LPAREN | TokenType::VECTOR_START | TokenType::HASH_TABLE_START => {
+                    let (pos, end) = (t.pos, self.lexer.pos());
+                    let result = match t.r#type {
+                        TokenType::LPAREN => self.parse_list(ctx, e),
+                        TokenType::VECTOR_START => self.parse_vector(ctx, e),
+                        _ => self.parse_hash_table(ctx, e),
+                    };
+                    result.map(Some).map_err(|err| self.unclosed(err, pos, end))
+                }
+                TokenType::RPAREN => Err(self.error(ParseErrorType::UnexpectedRPAREN)),
+                TokenType::QUOTE => Ok(Some(self.parse_abbreviation("quote", ctx, e)?)),
+                TokenType::QUASIQUOTE => Ok(Some(self.parse_abbreviation("quasiquote", ctx, e)?)),
+                TokenType::UNQUOTE => Ok(Some(self.parse_abbreviation("unquote", ctx, e)?)),
+                TokenType::UNQUOTE_SPLICING => {
+                    Ok(Some(self.parse_abbreviation("unquote-splicing", ctx, e)?))
+                }
+                TokenType::READER_MACRO | TokenType::DISPATCH_MACRO => {
+                    let c = t.val.chars().last().unwrap();
+                    let proc = match t.r#type {
+                        TokenType::READER_MACRO => ctx.readtable.get_macro(c),
+                        _ => ctx.readtable.get_dispatch(c),
+                    };
+                    let start = t.pos + t.val.len();
+                    Ok(Some(self.read_macro(
+                        proc.expect("lexed from the readtable"),
+                        start,
+                        ctx,
+                        e,
+                    )?))
+                }
+                TokenType::LABEL_DEF | TokenType::LABEL_REF => {
+                    let n = match t.val[1..t.val.len() - 1].parse::<u64>() {
+                        Ok(n) => n,
+                        Err(_) => return Err(self.error(ParseErrorType::MalformedNumber)),
+                    };
+                    if t.r#type == TokenType::LABEL_DEF {
+                        return Ok(Some(self.parse_label(n, ctx, e)?));
+                    }
+                    let result = match self.labels.get(&n) {
+                        Some(root) => root.handle(),
+                        None => return Err(self.error(ParseErrorType::UndefinedLabel(n))),
+                    };
+                    self.advance(ctx)?;
+                    Ok(Some(result))
+                }
+                TokenType::DOT | TokenType::DATUM_COMMENT => {
+                    Err(self.error(ParseErrorType::MalformedList))
+                }
             },
         }
     }
 }
+
+/// Replaces `placeholder` with `datum` in the pairs, vectors and hash tables
+/// reachable from `datum`. The hash tables are rebuilt afterwards, since
+/// patching a key can change its hash.
+fn replace_placeholder(ctx: &mut Context, datum: Handle, placeholder: Handle) {
+    let fix = |h: &mut Handle| {
+        if *h == placeholder {
+            *h = datum;
+        }
+    };
+    let mut seen = HashSet::new();
+    let mut tables = vec![];
+    let mut todo = vec![datum];
+    while let Some(h) = todo.pop() {
+        if !matches!(h, Handle::Cell(_)) || !seen.insert(h) {
+            continue;
+        }
+        match ctx.heap.get_mut_ref(h) {
+            Sexp::Pair(car, cdr) => {
+                fix(car);
+                fix(cdr);
+                todo.push(*car);
+                todo.push(*cdr);
+            }
+            Sexp::Vector(v) => {
+                v.iter_mut().for_each(fix);
+                todo.extend(v.iter().copied());
+            }
+            Sexp::HashTable(t) => {
+                for entry in t.entries() {
+                    todo.push(entry.key);
+                    todo.push(entry.val);
+                }
+                tables.push(h);
+            }
+            _ => {}
+        }
+    }
+    for h in tables {
+        let (kind, entries) = match ctx.heap.get_ref(h).as_ref() {
+            Sexp::HashTable(t) => (t.kind(), t.entries().to_vec()),
+            _ => unreachable!(),
+        };
+        let mut table = HashTable::new(kind);
+        for mut entry in entries {
+            fix(&mut entry.key);
+            fix(&mut entry.val);
+            let (hash, slot) = table.find(entry.key, &ctx.heap);
+            table.insert(hash, slot, entry.key, entry.val);
+        }
+        *ctx.heap.get_mut_ref(h) = Sexp::HashTable(table);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextOptions;
+
+    /// The message and notes of each syntax error in `text`.
+    fn check(text: &str) -> Vec<(String, Vec<String>)> {
+        let mut ctx = Context::new(ContextOptions::default());
+        let mut e = Evaluator::new(&mut ctx);
+        let source = Rc::new(SourceFile::new("test", text.to_string()));
+        let errors = Parser::new(&source).check(&mut ctx, &mut e);
+        errors
+            .into_iter()
+            .map(|err| {
+                let notes = err.notes.into_iter().map(|note| note.message).collect();
+                (err.r#type.to_string(), notes)
+            })
+            .collect()
+    }
+
+    fn unclosed(opens: &[&str]) -> (String, Vec<String>) {
+        let notes = opens
+            .iter()
+            .map(|open| format!("unclosed `{}` opened here", open))
+            .collect();
+        (String::from("unexpected end of file"), notes)
+    }
+
+    #[test]
+    fn check_reports_each_form() {
+        let errors = check("(a 1/0) (b 2/0)\n)\n(c)");
+        let messages: Vec<&str> = errors.iter().map(|(m, _)| m.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "rational with a zero denominator",
+                "rational with a zero denominator",
+                "unexpected `)`"
+            ]
+        );
+    }
+
+    #[test]
+    fn check_reports_unclosed_lists_after_an_error() {
+        let errors = check("(e 1/0");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[1], unclosed(&["("]));
+
+        let errors = check("(a #(1/0 (b\n(c)\n");
+        assert_eq!(errors[1], unclosed(&["(", "#(", "("]));
+    }
+
+    #[test]
+    fn check_reports_eof_once() {
+        assert_eq!(check("(a (b)"), [unclosed(&["("])]);
+    }
+
+    #[test]
+    fn labels_do_not_intern_symbols() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let mut e = Evaluator::new(&mut ctx);
+        let text = "'#0=(a . #0#) '#1=#(#1#) '(#2=(b) #2#)".repeat(10);
+        let source = Rc::new(SourceFile::new("test", text));
+        let mut parser = Parser::new(&source);
+        for _ in 0..3 {
+            parser.next_form(&mut ctx, &mut e).unwrap();
+        }
+        let symbols = ctx.interner.names().count();
+        while parser.next_form(&mut ctx, &mut e).unwrap().is_some() {}
+        assert_eq!(ctx.interner.names().count(), symbols);
+    }
+
+    #[test]
+    fn label_errors() {
+        let errors = check("#0# '(#1=(a) #1=(b)) #2=#2# #3=");
+        let messages: Vec<&str> = errors.iter().map(|(m, _)| m.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "undefined datum label `#0#`",
+                "datum label `#1=` defined twice",
+                "undefined datum label `#2#`",
+                "unexpected end of file"
+            ]
+        );
+    }
+}
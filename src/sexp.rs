@@ -1,11 +1,19 @@
 use crate::context::Context;
-use crate::context::gc_heap::Handle;
+use crate::context::gc_heap::{Handle, Mark};
 use crate::evaluator::env::Env;
 use crate::evaluator::{EvalError, Evaluator};
+use crate::number::bigint::BigInt;
+use crate::number::rational::Rational;
+pub mod hash_table;
+pub mod port;
+pub mod printer;
+use hash_table::HashTable;
+use port::Port;
 
 pub type Symbol = u64;
 pub type BuiltinFn = fn(&mut Evaluator, &mut Context) -> Result<(), EvalError>;
 
+#[derive(Clone)]
 pub struct Closure {
     pub env: Handle,
     pub vars: Vec<Handle>,
@@ -13,104 +21,134 @@ pub struct Closure {
     pub body: Handle,
 }
 
+#[derive(Clone)]
 pub enum Sexp {
     Integer(i64),
+    BigInt(BigInt),
+    Rational(Rational),
+    Real(f64),
     Symbol(Symbol),
     String(String),
     Pair(Handle, Handle),
+    Vector(Vec<Handle>),
+    HashTable(HashTable),
+    Port(Port),
     Nil,
+    Boolean(bool),
+    Char(char),
+    Inert,
+    Ignore,
 
     Env(Env),
-    Builtin(BuiltinFn),
+    Builtin(BuiltinFn, &'static str),
     Closure(Closure),
-    WrappedProc(Handle)
+    WrappedProc(Handle),
 }
 
-impl Sexp {
-    pub fn to_string(&self, ctx: &Context) -> String {
-        match self {
-            Sexp::Integer(i) => format!("{}", i),
-            Sexp::Symbol(s) => format!(
-                "{}",
-                ctx.interner
-                    .string_from_symbol(*s)
-                    .unwrap_or(&String::from("<unknown symbol>"))
-            ),
-            Sexp::String(s) => format!("{:?}", s),
-            Sexp::Pair(car_handle, cdr_handle) => {
-                let car = ctx.heap.get_ref(*car_handle);
-                let cdr = ctx.heap.get_ref(*cdr_handle);
-                let mut result = String::new();
-                result.push_str("(");
-                result.push_str(car.to_string(ctx).as_str());
-                let mut it = cdr;
-                loop {
-                    match it {
-                        Sexp::Pair(car_handle, cdr_handle) => {
-                            let car = ctx.heap.get_ref(*car_handle);
-                            let cdr = ctx.heap.get_ref(*cdr_handle);
-                            result.push(' ');
-                            result.push_str(car.to_string(ctx).as_str());
-                            it = &cdr;
-                        }
-                        Sexp::Nil => break,
-                        s => {
-                            result.push_str(" . ");
-                            result.push_str(s.to_string(ctx).as_str());
-                            break;
-                        }
-                    }
-                }
-                result.push(')');
-                result
-            }
-            Sexp::Nil => String::from("()"),
-            Sexp::Env(_) => String::from("<env>"),
-            Sexp::Builtin(_) => String::from("<builtin>"),
-            Sexp::Closure(_) => String::from("<closure>"),
-            Sexp::WrappedProc(_) => String::from("<wrapped proc>"),
+/// Prints `s` as a string literal that reads back to the same string.
+pub fn write_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            c if c.is_control() => result.push_str(&format!("\\x{:x};", c as u32)),
+            c => result.push(c),
         }
     }
+    result.push('"');
+    result
+}
 
-    pub fn into_list<'a>(self: &Self, ctx: &'a Context) -> Result<Vec<&'a Sexp>, EvalError> {
-        let mut list: Vec<&Sexp> = vec![];
-        match self {
-            Sexp::Pair(car_h, cdr_h) => {
-                list.push(ctx.heap.get_ref(*car_h));
-                let cdr = ctx.heap.get_ref(*cdr_h);
-                let mut rest = cdr.into_list(ctx)?;
-                list.append(&mut rest);
-                Ok(list)
-            }
-            Sexp::Nil => Ok(list),
-            _ => Err(EvalError::TypeError(String::from("expected a list"))),
-        }
+/// The names `#\name` character literals can use, besides single characters
+/// and `#\x` hex escapes.
+pub const CHAR_NAMES: &[(&str, char)] = &[
+    ("alarm", '\u{7}'),
+    ("backspace", '\u{8}'),
+    ("delete", '\u{7f}'),
+    ("escape", '\u{1b}'),
+    ("newline", '\n'),
+    ("null", '\0'),
+    ("return", '\r'),
+    ("space", ' '),
+    ("tab", '\t'),
+];
+
+/// Prints `c` as a character literal that reads back to the same character.
+pub fn write_char(c: char) -> String {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => format!("#\\{}", name),
+        None if c.is_control() || c.is_whitespace() => format!("#\\x{:x}", c as u32),
+        None => format!("#\\{}", c),
     }
+}
 
-    pub fn into_handle_list(self: &Self, ctx: &Context) -> Result<Vec<Handle>, EvalError> {
-        let mut list: Vec<Handle> = vec![];
+impl Mark for Closure {
+    fn mark(&self, gray: &mut Vec<Handle>) {
+        gray.push(self.env);
+        gray.extend(self.vars.iter().copied());
+        gray.push(self.sym);
+        gray.push(self.body);
+    }
+}
+
+impl Mark for Sexp {
+    fn mark(&self, gray: &mut Vec<Handle>) {
         match self {
-            Sexp::Pair(car_h, cdr_h) => {
-                list.push(*car_h);
-                let cdr = ctx.heap.get_ref(*cdr_h);
-                let mut rest = cdr.into_handle_list(ctx)?;
-                list.append(&mut rest);
-                Ok(list)
+            Sexp::Pair(car, cdr) => {
+                gray.push(*car);
+                gray.push(*cdr);
+            }
+            Sexp::Vector(v) => gray.extend(v.iter().copied()),
+            Sexp::HashTable(t) => {
+                for entry in t.entries() {
+                    gray.push(entry.key);
+                    gray.push(entry.val);
+                }
             }
-            Sexp::Nil => Ok(list),
-            _ => Err(EvalError::TypeError(String::from("expected a list"))),
+            Sexp::Env(env) => env.mark(gray),
+            Sexp::Closure(c) => c.mark(gray),
+            Sexp::WrappedProc(p) => gray.push(*p),
+            Sexp::Integer(_)
+            | Sexp::BigInt(_)
+            | Sexp::Rational(_)
+            | Sexp::Real(_)
+            | Sexp::Symbol(_)
+            | Sexp::String(_)
+            | Sexp::Nil
+            | Sexp::Boolean(_)
+            | Sexp::Char(_)
+            | Sexp::Inert
+            | Sexp::Ignore
+            | Sexp::Port(_)
+            | Sexp::Builtin(_, _) => {}
         }
     }
+}
 
-    pub fn into_integer(self: &Self, ctx: &Context) -> Result<i64, EvalError> {
-        match self {
-            Sexp::Integer(i) => Ok(*i),
-            _ => Err(EvalError::TypeError(String::from("expected an integer"))),
+impl Sexp {
+    pub fn to_handle_list(&self, ctx: &Context) -> Result<Vec<Handle>, EvalError> {
+        let mut list: Vec<Handle> = vec![];
+        let (mut car_h, mut cdr_h) = match self {
+            Sexp::Pair(car_h, cdr_h) => (*car_h, *cdr_h),
+            Sexp::Nil => return Ok(list),
+            _ => return Err(EvalError::TypeError(String::from("expected a list"))),
+        };
+        loop {
+            list.push(car_h);
+            match ctx.heap.try_get_ref(cdr_h)?.as_ref() {
+                Sexp::Pair(car, cdr) => (car_h, cdr_h) = (*car, *cdr),
+                Sexp::Nil => return Ok(list),
+                _ => return Err(EvalError::TypeError(String::from("expected a list"))),
+            }
         }
     }
 
     pub fn from_handle_list(l: Vec<Handle>, ctx: &mut Context) -> Handle {
-        let mut result = ctx.heap.alloc(Sexp::Nil);
+        let mut result = Handle::Nil;
         for i in l.iter().rev() {
             result = ctx.heap.alloc(Sexp::Pair(*i, result));
         }
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use crate::context::gc_heap::{GcHeap, Handle};
+use crate::sexp::Sexp;
+
+/// How a hash table compares its keys. `Eq` compares handles, `Eqv` also
+/// compares numbers by value, and `Equal` also compares strings, pairs and
+/// vectors by content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Eq,
+    Eqv,
+    Equal,
+}
+
+impl TableKind {
+    pub fn from_name(name: &str) -> Option<TableKind> {
+        match name {
+            "eq" => Some(TableKind::Eq),
+            "eqv" => Some(TableKind::Eqv),
+            "equal" => Some(TableKind::Equal),
+            _ => None,
+        }
+    }
+
+    /// The hash of `key`, consistent with `equals`. `Equal` tables only look
+    /// at the first few nodes of a structure, so cyclic keys still hash.
+    pub fn hash(&self, key: Handle, heap: &GcHeap) -> u64 {
+        let mut hasher = Fnv::new();
+        let mut todo = vec![key];
+        let mut budget = 64;
+        while let Some(h) = todo.pop() {
+            if budget == 0 {
+                break;
+            }
+            budget -= 1;
+            match (self, heap.get_ref(h).as_ref()) {
+                (TableKind::Eq, _) => h.hash(&mut hasher),
+                (_, Sexp::BigInt(b)) => b.hash(&mut hasher),
+                (_, Sexp::Rational(q)) => q.hash(&mut hasher),
+                (_, Sexp::Real(r)) => r.to_bits().hash(&mut hasher),
+                (TableKind::Equal, Sexp::String(s)) => s.hash(&mut hasher),
+                (TableKind::Equal, Sexp::Pair(car, cdr)) => {
+                    0u8.hash(&mut hasher);
+                    todo.push(*cdr);
+                    todo.push(*car);
+                }
+                (TableKind::Equal, Sexp::Vector(v)) => {
+                    v.len().hash(&mut hasher);
+                    todo.extend(v.iter().rev());
+                }
+                _ => h.hash(&mut hasher),
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Whether `a` and `b` are the same key. Pairs and vectors that are
+    /// already being compared are assumed equal when they come up again, so
+    /// cyclic keys compare equal when they unfold to the same structure.
+    pub fn equals(&self, a: Handle, b: Handle, heap: &GcHeap) -> bool {
+        let mut todo = vec![(a, b)];
+        let mut seen = HashSet::new();
+        while let Some((a, b)) = todo.pop() {
+            if a == b || !seen.insert((a, b)) {
+                continue;
+            }
+            if *self == TableKind::Eq {
+                return false;
+            }
+            match (heap.get_ref(a).as_ref(), heap.get_ref(b).as_ref()) {
+                (Sexp::BigInt(x), Sexp::BigInt(y)) if x == y => {}
+                (Sexp::Rational(x), Sexp::Rational(y)) if x == y => {}
+                (Sexp::Real(x), Sexp::Real(y)) if x.to_bits() == y.to_bits() => {}
+                (Sexp::String(x), Sexp::String(y)) if *self == TableKind::Equal && x == y => {}
+                (Sexp::Pair(car1, cdr1), Sexp::Pair(car2, cdr2)) if *self == TableKind::Equal => {
+                    todo.push((*cdr1, *cdr2));
+                    todo.push((*car1, *car2));
+                }
+                (Sexp::Vector(x), Sexp::Vector(y))
+                    if *self == TableKind::Equal && x.len() == y.len() =>
+                {
+                    todo.extend(x.iter().copied().zip(y.iter().copied()));
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// FNV-1a, so hashes don't depend on a random seed and can be saved in heap
+/// images.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub hash: u64,
+    pub key: Handle,
+    pub val: Handle,
+}
+
+/// A hash table from handles to handles. Hashing and comparing keys needs
+/// the heap, so lookups are split in two: `find` runs with the heap borrowed
+/// and returns the key's hash and slot, which `insert` and `remove` then use
+/// on the table borrowed mutably from the heap.
+#[derive(Debug, Clone)]
+pub struct HashTable {
+    kind: TableKind,
+    entries: Vec<Entry>,
+    index: HashMap<u64, Vec<usize>>,
+}
+
+impl HashTable {
+    pub fn new(kind: TableKind) -> Self {
+        Self {
+            kind,
+            entries: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    /// Rebuilds a table from entries whose hashes were already computed.
+    pub fn from_entries(kind: TableKind, entries: Vec<Entry>) -> Self {
+        let mut table = Self::new(kind);
+        for entry in entries {
+            table.insert(entry.hash, None, entry.key, entry.val);
+        }
+        table
+    }
+
+    pub fn kind(&self) -> TableKind {
+        self.kind
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The hash of `key` and the slot of its entry, if it has one.
+    pub fn find(&self, key: Handle, heap: &GcHeap) -> (u64, Option<usize>) {
+        let hash = self.kind.hash(key, heap);
+        let slot = self.index.get(&hash).and_then(|slots| {
+            slots
+                .iter()
+                .copied()
+                .find(|slot| self.kind.equals(self.entries[*slot].key, key, heap))
+        });
+        (hash, slot)
+    }
+
+    pub fn get(&self, key: Handle, heap: &GcHeap) -> Option<Handle> {
+        self.find(key, heap).1.map(|slot| self.entries[slot].val)
+    }
+
+    /// Sets the value in `slot`, or adds a new entry when there is none.
+    pub fn insert(&mut self, hash: u64, slot: Option<usize>, key: Handle, val: Handle) {
+        match slot {
+            Some(slot) => self.entries[slot].val = val,
+            None => {
+                self.index.entry(hash).or_default().push(self.entries.len());
+                self.entries.push(Entry { hash, key, val });
+            }
+        }
+    }
+
+    /// Removes the entry in `slot`; the last entry takes its place.
+    pub fn remove(&mut self, slot: usize) {
+        let last = self.entries.len() - 1;
+        self.unindex(self.entries[slot].hash, slot);
+        if slot != last {
+            let moved = self.entries[last].hash;
+            self.unindex(moved, last);
+            self.index.entry(moved).or_default().push(slot);
+        }
+        self.entries.swap_remove(slot);
+    }
+
+    fn unindex(&mut self, hash: u64, slot: usize) {
+        if let Some(slots) = self.index.get_mut(&hash) {
+            slots.retain(|s| *s != slot);
+            if slots.is_empty() {
+                self.index.remove(&hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The cyclic list `(x x x ...)` with a cycle of `period` pairs.
+    fn cycle(heap: &mut GcHeap, x: i64, period: usize) -> Handle {
+        let last = heap.alloc(Sexp::Pair(Handle::Integer(x), Handle::Nil));
+        let mut first = last;
+        for _ in 1..period {
+            first = heap.alloc(Sexp::Pair(Handle::Integer(x), first));
+        }
+        *heap.get_mut_ref(last) = Sexp::Pair(Handle::Integer(x), first);
+        first
+    }
+
+    #[test]
+    fn equal_keys_may_be_cyclic() {
+        let mut heap = GcHeap::new(None);
+        let a = cycle(&mut heap, 1, 1);
+        let b = cycle(&mut heap, 1, 3);
+        let c = cycle(&mut heap, 2, 2);
+        assert!(TableKind::Equal.equals(a, b, &heap));
+        assert!(!TableKind::Equal.equals(a, c, &heap));
+        assert!(!TableKind::Eqv.equals(a, b, &heap));
+    }
+
+    #[test]
+    fn cyclic_keys_can_be_looked_up() {
+        let mut heap = GcHeap::new(None);
+        let key = cycle(&mut heap, 1, 1);
+        let other = cycle(&mut heap, 1, 2);
+        let mut table = HashTable::new(TableKind::Equal);
+        let (hash, slot) = table.find(key, &heap);
+        table.insert(hash, slot, key, Handle::Integer(7));
+        assert_eq!(table.get(other, &heap), Some(Handle::Integer(7)));
+        assert_eq!(table.get(Handle::Integer(1), &heap), None);
+    }
+}
@@ -0,0 +1,49 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::context::spans::SourceFile;
+
+/// A character input port over a source file. Clones share their
+/// position, so a reader macro reading from the port moves the parser that
+/// handed it out.
+#[derive(Debug, Clone)]
+pub struct Port {
+    source: Rc<SourceFile>,
+    pos: Rc<Cell<usize>>,
+}
+
+impl Port {
+    pub fn new(source: Rc<SourceFile>, pos: usize) -> Self {
+        Self {
+            source,
+            pos: Rc::new(Cell::new(pos)),
+        }
+    }
+
+    pub fn source(&self) -> &Rc<SourceFile> {
+        &self.source
+    }
+
+    pub fn text(&self) -> &str {
+        self.source.text()
+    }
+
+    /// The byte offset of the next character.
+    pub fn pos(&self) -> usize {
+        self.pos.get()
+    }
+
+    pub fn set_pos(&self, pos: usize) {
+        self.pos.set(pos);
+    }
+
+    pub fn peek_char(&self) -> Option<char> {
+        self.text()[self.pos()..].chars().next()
+    }
+
+    pub fn read_char(&self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.set_pos(self.pos() + c.len_utf8());
+        Some(c)
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+
+use super::hash_table::TableKind;
+use super::{Sexp, write_char, write_string};
+use crate::context::Context;
+use crate::context::gc_heap::Handle;
+use crate::number::write_real;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrintMode {
+    /// Output that reads back as the same datum.
+    Write,
+    /// Strings and characters as their raw text.
+    Display,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PrintOptions {
+    pub mode: PrintMode,
+    /// How deep lists, vectors and hash tables may nest before the deeper
+    /// ones are printed as `...`.
+    pub max_depth: Option<usize>,
+    /// How many elements of a list, vector or hash table are printed before
+    /// the rest is elided as `...`.
+    pub max_length: Option<usize>,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self {
+            mode: PrintMode::Write,
+            max_depth: None,
+            max_length: None,
+        }
+    }
+}
+
+/// What is left to print, kept on an explicit stack so that deep structures
+/// don't overflow the Rust stack.
+enum Work {
+    Datum(Handle, usize),
+    Text(&'static str),
+    /// The rest of a list after its first `count` elements.
+    Rest(Handle, usize, usize),
+}
+
+/// Prints the datum `h`. Pairs, vectors and hash tables that are reached
+/// more than once, by sharing or through a cycle, are labelled `#n=` where
+/// they are first printed and referred to as `#n#` after that, so printing
+/// always terminates.
+pub fn print(h: Handle, ctx: &Context, options: PrintOptions) -> String {
+    let mut printer = Printer {
+        ctx,
+        options,
+        shared: find_shared(h, ctx),
+        labels: HashMap::new(),
+        out: String::new(),
+    };
+    printer.run(h);
+    printer.out
+}
+
+fn children(sexp: &Sexp) -> Option<Vec<Handle>> {
+    match sexp {
+        Sexp::Pair(car, cdr) => Some(vec![*car, *cdr]),
+        Sexp::Vector(v) => Some(v.clone()),
+        Sexp::HashTable(t) => Some(
+            t.entries()
+                .iter()
+                .flat_map(|entry| [entry.key, entry.val])
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// The pairs, vectors and hash tables reachable more than once from `root`.
+fn find_shared(root: Handle, ctx: &Context) -> HashSet<Handle> {
+    let mut seen = HashSet::new();
+    let mut shared = HashSet::new();
+    let mut todo = vec![root];
+    while let Some(h) = todo.pop() {
+        if let Handle::Cell(_) = h
+            && let Some(children) = children(ctx.heap.get_ref(h).as_ref())
+        {
+            if seen.insert(h) {
+                todo.extend(children);
+            } else {
+                shared.insert(h);
+            }
+        }
+    }
+    shared
+}
+
+struct Printer<'a> {
+    ctx: &'a Context,
+    options: PrintOptions,
+    shared: HashSet<Handle>,
+    labels: HashMap<Handle, usize>,
+    out: String,
+}
+
+impl<'a> Printer<'a> {
+    fn run(&mut self, root: Handle) {
+        let mut work = vec![Work::Datum(root, 0)];
+        while let Some(item) = work.pop() {
+            match item {
+                Work::Text(text) => self.out.push_str(text),
+                Work::Datum(h, depth) => self.datum(h, depth, &mut work),
+                Work::Rest(h, depth, count) => self.rest(h, depth, count, &mut work),
+            }
+        }
+    }
+
+    fn at_length_limit(&self, count: usize) -> bool {
+        self.options.max_length.is_some_and(|max| count >= max)
+    }
+
+    fn datum(&mut self, h: Handle, depth: usize, work: &mut Vec<Work>) {
+        let ctx = self.ctx;
+        let sexp = ctx.heap.get_ref(h);
+        let compound = matches!(
+            sexp.as_ref(),
+            Sexp::Pair(_, _) | Sexp::Vector(_) | Sexp::HashTable(_)
+        );
+        if compound {
+            if self.options.max_depth.is_some_and(|max| depth >= max) {
+                self.out.push_str("...");
+                return;
+            }
+            if self.shared.contains(&h) {
+                if let Some(n) = self.labels.get(&h) {
+                    self.out.push_str(&format!("#{}#", n));
+                    return;
+                }
+                let n = self.labels.len();
+                self.labels.insert(h, n);
+                self.out.push_str(&format!("#{}=", n));
+            }
+        }
+        match sexp.as_ref() {
+            Sexp::Pair(car, cdr) => {
+                self.out.push('(');
+                work.push(Work::Rest(*cdr, depth, 1));
+                work.push(Work::Datum(*car, depth + 1));
+            }
+            Sexp::Vector(v) => {
+                self.out.push_str("#(");
+                self.sequence(v.iter().map(|h| vec![*h]), depth, work);
+            }
+            Sexp::HashTable(t) => {
+                self.out.push_str(match t.kind() {
+                    TableKind::Eq => "#hasheq(",
+                    TableKind::Eqv => "#hasheqv(",
+                    TableKind::Equal => "#hash(",
+                });
+                let entries = t.entries().iter().map(|entry| vec![entry.key, entry.val]);
+                self.sequence(entries, depth, work);
+            }
+            sexp => {
+                let text = self.atom(sexp);
+                self.out.push_str(&text);
+            }
+        }
+    }
+
+    /// Schedules the elements of a vector, or the `(key . value)` entries of
+    /// a hash table, and the closing `)`.
+    fn sequence<I>(&self, elements: I, depth: usize, work: &mut Vec<Work>)
+    where
+        I: ExactSizeIterator<Item = Vec<Handle>>,
+    {
+        let len = elements.len();
+        let shown = self.options.max_length.map_or(len, |max| len.min(max));
+        let mut items = vec![];
+        for (i, element) in elements.take(shown).enumerate() {
+            if i != 0 {
+                items.push(Work::Text(" "));
+            }
+            match element.as_slice() {
+                [h] => items.push(Work::Datum(*h, depth + 1)),
+                [key, val] => {
+                    items.push(Work::Text("("));
+                    items.push(Work::Datum(*key, depth + 1));
+                    items.push(Work::Text(" . "));
+                    items.push(Work::Datum(*val, depth + 1));
+                    items.push(Work::Text(")"));
+                }
+                _ => unreachable!(),
+            }
+        }
+        if shown < len {
+            items.push(Work::Text(if shown == 0 { "..." } else { " ..." }));
+        }
+        items.push(Work::Text(")"));
+        work.extend(items.into_iter().rev());
+    }
+
+    /// Continues a list whose first `count` elements are printed with its
+    /// tail `h`. A tail that is labelled is printed after a dot, so the
+    /// label has a datum to go on.
+    fn rest(&mut self, h: Handle, depth: usize, count: usize, work: &mut Vec<Work>) {
+        let ctx = self.ctx;
+        match ctx.heap.get_ref(h).as_ref() {
+            Sexp::Nil => self.out.push(')'),
+            Sexp::Pair(car, cdr) if !self.shared.contains(&h) => {
+                if self.at_length_limit(count) {
+                    self.out.push_str(" ...)");
+                    return;
+                }
+                self.out.push(' ');
+                work.push(Work::Rest(*cdr, depth, count + 1));
+                work.push(Work::Datum(*car, depth + 1));
+            }
+            _ => {
+                self.out.push_str(" . ");
+                work.push(Work::Text(")"));
+                work.push(Work::Datum(h, depth + 1));
+            }
+        }
+    }
+
+    fn atom(&self, sexp: &Sexp) -> String {
+        let ctx = self.ctx;
+        match sexp {
+            Sexp::Integer(i) => format!("{}", i),
+            Sexp::BigInt(b) => b.to_string(),
+            Sexp::Rational(q) => q.to_string(),
+            Sexp::Real(r) => write_real(*r),
+            Sexp::Symbol(s) => {
+                let name = ctx
+                    .interner
+                    .string_from_symbol(*s)
+                    .unwrap_or("<unknown symbol>");
+                if ctx.interner.is_interned(*s) {
+                    name.to_string()
+                } else {
+                    format!("#:{}", name)
+                }
+            }
+            Sexp::String(s) => match self.options.mode {
+                PrintMode::Write => write_string(s),
+                PrintMode::Display => s.clone(),
+            },
+            Sexp::Char(c) => match self.options.mode {
+                PrintMode::Write => write_char(*c),
+                PrintMode::Display => c.to_string(),
+            },
+            Sexp::Nil => String::from("()"),
+            Sexp::Boolean(true) => String::from("#t"),
+            Sexp::Boolean(false) => String::from("#f"),
+            Sexp::Inert => String::from("#inert"),
+            Sexp::Ignore => String::from("#ignore"),
+            Sexp::Port(_) => String::from("<port>"),
+            Sexp::Env(_) => String::from("<env>"),
+            Sexp::Builtin(_, _) => String::from("<builtin>"),
+            Sexp::Closure(_) => String::from("<closure>"),
+            Sexp::WrappedProc(_) => String::from("<wrapped proc>"),
+            Sexp::Pair(_, _) | Sexp::Vector(_) | Sexp::HashTable(_) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextOptions;
+
+    fn list(ctx: &mut Context, items: &[Handle]) -> Handle {
+        Sexp::from_handle_list(items.to_vec(), ctx)
+    }
+
+    fn write(h: Handle, ctx: &Context) -> String {
+        print(h, ctx, PrintOptions::default())
+    }
+
+    #[test]
+    fn shared_data_is_labelled() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let x = Handle::Symbol(ctx.interner.intern("x"));
+        let shared = list(&mut ctx, &[x]);
+        let outer = list(&mut ctx, &[shared, shared, Handle::Integer(1)]);
+        assert_eq!(write(outer, &ctx), "(#0=(x) #0# 1)");
+    }
+
+    #[test]
+    fn cyclic_list() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let a = Handle::Symbol(ctx.interner.intern("a"));
+        let l = list(&mut ctx, &[a, Handle::Integer(2)]);
+        if let Sexp::Pair(_, cdr) = ctx.heap.get_ref(l).as_ref() {
+            *ctx.heap.get_mut_ref(*cdr) = Sexp::Pair(Handle::Integer(2), l);
+        }
+        assert_eq!(write(l, &ctx), "#0=(a 2 . #0#)");
+    }
+
+    #[test]
+    fn cycle_through_the_cdr_of_a_sublist() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let tail = list(&mut ctx, &[Handle::Integer(2)]);
+        let l = ctx.heap.alloc(Sexp::Pair(Handle::Integer(1), tail));
+        *ctx.heap.get_mut_ref(tail) = Sexp::Pair(Handle::Integer(2), tail);
+        assert_eq!(write(l, &ctx), "(1 . #0=(2 . #0#))");
+    }
+
+    #[test]
+    fn cyclic_vector() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let v = ctx.heap.alloc(Sexp::Vector(vec![]));
+        *ctx.heap.get_mut_ref(v) = Sexp::Vector(vec![Handle::Integer(1), v]);
+        assert_eq!(write(v, &ctx), "#0=#(1 #0#)");
+    }
+
+    #[test]
+    fn labels_are_numbered_in_print_order() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let a = list(&mut ctx, &[Handle::Integer(1)]);
+        let b = list(&mut ctx, &[Handle::Integer(2)]);
+        let outer = list(&mut ctx, &[b, a, b, a]);
+        assert_eq!(write(outer, &ctx), "(#0=(2) #1=(1) #0# #1#)");
+    }
+
+    #[test]
+    fn limits_elide() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let items: Vec<Handle> = (1..=5).map(Handle::Integer).collect();
+        let inner = list(&mut ctx, &items);
+        let outer = list(&mut ctx, &[inner]);
+        let options = PrintOptions {
+            max_length: Some(3),
+            ..PrintOptions::default()
+        };
+        assert_eq!(print(outer, &ctx, options), "((1 2 3 ...))");
+        let options = PrintOptions {
+            max_depth: Some(1),
+            ..PrintOptions::default()
+        };
+        assert_eq!(print(outer, &ctx, options), "(...)");
+    }
+
+    #[test]
+    fn display_mode_prints_raw_text() {
+        let mut ctx = Context::new(ContextOptions::default());
+        let s = ctx.heap.alloc(Sexp::String(String::from("a \"b\"")));
+        let l = list(&mut ctx, &[s, Handle::Char('c')]);
+        assert_eq!(write(l, &ctx), "(\"a \\\"b\\\"\" #\\c)");
+        let options = PrintOptions {
+            mode: PrintMode::Display,
+            ..PrintOptions::default()
+        };
+        assert_eq!(print(l, &ctx, options), "(a \"b\" c)");
+    }
+}